@@ -0,0 +1,29 @@
+// Compare the cost of detecting an unchanged aggregated book against the cost of the fan-out to
+// clients that the detection avoids.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+use obagg::bench::{ChangeDetection, FanOut};
+
+const DEPTH: usize = 10;
+
+fn change_detection(c: &mut Criterion) {
+    let detection = ChangeDetection::new(DEPTH);
+    c.bench_function("compare unchanged book", |b| {
+        b.iter(|| criterion::black_box(detection.unchanged()))
+    });
+
+    let mut group = c.benchmark_group("fan-out");
+    for clients in [1, 10, 100] {
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, &n| {
+            b.iter_batched(
+                || FanOut::new(DEPTH, n),
+                FanOut::publish,
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, change_detection);
+criterion_main!(benches);
@@ -1,129 +1,281 @@
-use log::{debug, error};
-use std::{collections::HashMap, error::Error};
-use tokio::sync::{mpsc, RwLock};
+use async_stream::stream;
+use log::{debug, error, warn};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+};
+use tokio::{
+    sync::{broadcast, mpsc, watch, Mutex, RwLock},
+    time::Instant,
+};
+use tokio_stream::Stream;
 use tonic::Status;
 use uuid::Uuid;
 
 use crate::utils;
 use crate::{
-    config,
-    definitions::{Orderbook, Orderbooks},
-    orderbook::{Level, Summary},
+    analytics, arbitrage,
+    candles::{CandleBuilder, CandleHistory, CONSOLIDATED},
+    config::{self, SlowClientPolicy},
+    definitions::{MarketData, Orderbook, Orderbooks, BINANCE, BITSTAMP},
+    history::History,
+    implied,
+    metrics::Metrics,
+    normalize::{self, Markets},
+    orderbook::{ArbitrageOpportunity, Bbo, Candle, Level, Summary, Trade},
+    store::Store,
+    subscriber::{Delivery, Subscriber},
+    view::View,
 };
 
+// What the aggregator publishes besides the summaries of its subscribers, shared with the gRPC
+// server.
+pub struct Outputs {
+    // latest full aggregated orderbook.
+    pub book: RwLock<Orderbook>,
+    // arbitrage opportunities detected across exchanges.
+    pub arbitrage: broadcast::Sender<ArbitrageOpportunity>,
+    // best bid and offer of the aggregated book, only updated when it changes.
+    pub bbo: watch::Sender<Bbo>,
+    // trades of all exchanges.
+    pub trades: broadcast::Sender<Trade>,
+    // completed OHLCV bars, with the latest ones kept for new clients.
+    pub candles: Mutex<CandleHistory>,
+}
+
+impl Outputs {
+    pub fn new() -> Self {
+        Outputs {
+            book: RwLock::new(Orderbook::new()),
+            arbitrage: broadcast::channel(1024).0,
+            bbo: watch::channel(Bbo::default()).0,
+            trades: broadcast::channel(1024).0,
+            candles: Mutex::new(CandleHistory::new()),
+        }
+    }
+}
+
+impl Default for Outputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub async fn aggregate_orderbooks(
     conf: &config::Server,
-    rx: &mut mpsc::Receiver<Result<Orderbooks, Status>>,
-    tx_pool: &RwLock<HashMap<Uuid, mpsc::Sender<Result<Summary, Status>>>>,
+    rx: &mut mpsc::Receiver<Result<MarketData, Status>>,
+    tx_pool: &RwLock<HashMap<Uuid, Subscriber>>,
+    outputs: &Outputs,
+    history: Option<&History>,
+    store: Option<&Store>,
+    metrics: &Metrics,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut binance_ob_cache = Orderbook::new();
-    let mut bitstamp_ob_cache = Orderbook::new();
+    let policy = conf
+        .slow_client_policy
+        .unwrap_or(SlowClientPolicy::Conflate);
+    let max_client_lag = conf.max_client_lag.unwrap_or(100);
+    // latest book of the ticker on each exchange, keyed by exchange label.
+    let mut caches: BTreeMap<&'static str, Orderbook> = BTreeMap::new();
+    let mut markets = Markets::new();
+    let mut detector = arbitrage::Detector::new();
+    let mut candles = CandleBuilder::new(&conf.ticker);
+    let trades = conf.trades.unwrap_or(false);
+    // number of aggregated books built.
+    let mut sequence: u64 = 0;
+    // last summary published for each view, along with the clients that have received it.
+    let mut last_published: HashMap<View, (Summary, HashSet<Uuid>)> = HashMap::new();
 
     while let Some(msg) = rx.recv().await {
         match msg {
-            Ok(orderbook) => {
-                let mut aggregated_orderbook = Orderbook::new();
-
-                // match the type of incoming message, cache it in the appropriate book type and
-                // aggregate the cache of the other book type into the aggregated_orderbook.
+            Ok(MarketData::Trade(trade)) => {
+                let closed =
+                    candles.trade(&trade.exchange, trade.price, trade.amount, trade.timestamp);
+                publish_candles(&outputs.candles, closed).await;
+                // fails only when no client is listening.
+                let _ = outputs.trades.send(trade);
+            }
+            Ok(MarketData::Book(orderbook)) => {
+                // time the source message was received, books without one are timed on arrival.
+                let received = match &orderbook {
+                    Orderbooks::Binance(orderbook)
+                    | Orderbooks::Bitstamp(orderbook)
+                    | Orderbooks::Market { orderbook, .. } => orderbook.received,
+                };
+                let received = match received {
+                    0 => utils::now_micros(),
+                    received => received,
+                };
+                // cache the incoming book in the appropriate book type.
                 match orderbook {
                     Orderbooks::Binance(binance_orderbook) => {
                         debug!("Message from Binance received.");
-                        binance_ob_cache = binance_orderbook.clone();
-
-                        aggregated_orderbook.bids = bitstamp_ob_cache
-                            .bids
-                            .clone()
-                            .into_iter()
-                            .map(|k| utils::map_key(k, conf, true))
-                            .collect();
-
-                        aggregated_orderbook.bids.append(
-                            &mut binance_orderbook
-                                .bids
-                                .into_iter()
-                                .map(|k| utils::map_key(k, conf, true))
-                                .collect(),
-                        );
-
-                        aggregated_orderbook.asks = bitstamp_ob_cache
-                            .asks
-                            .clone()
-                            .into_iter()
-                            .map(|k| utils::map_key(k, conf, false))
-                            .collect();
-
-                        aggregated_orderbook.asks.append(
-                            &mut binance_orderbook
-                                .asks
-                                .into_iter()
-                                .map(|k| utils::map_key(k, conf, false))
-                                .collect(),
-                        );
+                        caches.insert(BINANCE, binance_orderbook);
                     }
                     Orderbooks::Bitstamp(bitstamp_orderbook) => {
                         debug!("Message from Bitstamp received.");
-                        bitstamp_ob_cache = bitstamp_orderbook.clone();
-                        aggregated_orderbook.bids = binance_ob_cache
-                            .bids
+                        caches.insert(BITSTAMP, bitstamp_orderbook);
+                    }
+                    Orderbooks::Market {
+                        exchange,
+                        ticker,
+                        orderbook,
+                    } => {
+                        debug!("Message from {} {} received.", exchange, ticker);
+                        markets.insert((exchange, ticker), orderbook);
+                    }
+                }
+
+                // books quoted in other currencies are converted at the latest rate, they are left
+                // out until a rate is known.
+                let mut derived = vec![];
+                for quote in conf.quotes.iter().flatten() {
+                    match normalize::rate(quote, &markets) {
+                        Some(rate) => derived.extend(normalize::convert(quote, &markets, rate)),
+                        None => debug!("No {} rate yet, {} left out.", quote.quote, quote.ticker),
+                    }
+                }
+                derived.extend(conf.implied.iter().flatten().filter_map(|market| {
+                    let book = implied::implied(market, &markets, conf.depth)?;
+                    Some((implied::label(market), book))
+                }));
+                let labelled: Vec<(&str, &Orderbook)> = caches
+                    .iter()
+                    .map(|(exchange, book)| (*exchange, book))
+                    .chain(derived.iter().map(|(label, book)| (label.as_str(), book)))
+                    .collect();
+                let books: Vec<&Orderbook> = labelled.iter().map(|(_, book)| *book).collect();
+
+                // aggregate the cached books of all exchanges into the aggregated_orderbook.
+                let mut aggregated_orderbook = Orderbook::new();
+                for book in &books {
+                    aggregated_orderbook.bids.extend(
+                        book.bids
                             .clone()
                             .into_iter()
-                            .map(|k| utils::map_key(k, conf, true))
-                            .collect();
-                        aggregated_orderbook.bids.append(
-                            &mut bitstamp_orderbook
-                                .bids
-                                .into_iter()
-                                .map(|k| utils::map_key(k, conf, true))
-                                .collect(),
-                        );
-                        aggregated_orderbook.asks = binance_ob_cache
-                            .asks
+                            .map(|k| utils::map_key(k, conf, true)),
+                    );
+                    aggregated_orderbook.asks.extend(
+                        book.asks
                             .clone()
                             .into_iter()
-                            .map(|k| utils::map_key(k, conf, false))
-                            .collect();
-                        aggregated_orderbook.asks.append(
-                            &mut bitstamp_orderbook
-                                .asks
-                                .into_iter()
-                                .map(|k| utils::map_key(k, conf, false))
-                                .collect(),
-                        );
+                            .map(|k| utils::map_key(k, conf, false)),
+                    );
+                }
+
+                // implied levels are not quoted by a venue, they are never traded against.
+                let tradable: Vec<(&str, &Orderbook)> = labelled
+                    .iter()
+                    .copied()
+                    .filter(|(label, _)| !implied::is_implied(label))
+                    .collect();
+                for opportunity in detector.update(&tradable, conf, Instant::now()) {
+                    // fails only when no client is listening.
+                    let _ = outputs.arbitrage.send(opportunity);
+                }
+
+                // bars without trades for a while are completed on the next book message, without
+                // trades the bars are built from the mid prices. Both are timed by the message, so
+                // that a replay builds the bars it was recorded with.
+                let mut closed = candles.roll(received);
+                if !trades {
+                    let sources = caches
+                        .iter()
+                        .map(|(exchange, book)| (*exchange, book))
+                        .chain([(CONSOLIDATED, &aggregated_orderbook)]);
+                    for (source, book) in sources {
+                        if let Some(mid) = mid(book) {
+                            closed.extend(candles.sample(source, mid, received));
+                        }
                     }
                 }
+                publish_candles(&outputs.candles, closed).await;
 
                 let aggregated_orderbook_reduced = aggregated_orderbook.reduce(conf.depth);
+                sequence += 1;
+                let reduced = &aggregated_orderbook_reduced;
+                if (history.is_some() || store.is_some())
+                    && !reduced.bids.is_empty()
+                    && !reduced.asks.is_empty()
+                {
+                    let summary = build_summary(reduced.clone());
+                    if let Some(history) = history {
+                        history.record(received, sequence, summary.clone()).await;
+                    }
+                    if let Some(store) = store {
+                        store.snapshot(received, sequence, summary);
+                    }
+                }
+                publish_bbo(&outputs.bbo, &aggregated_orderbook);
+                // the full aggregated book is kept for the queries walking the live book.
+                *outputs.book.write().await = aggregated_orderbook;
 
+                // books of auxiliary markets can arrive before any book of the ticker, nothing is
+                // published until both sides of the aggregated book are quoted.
+                if reduced.bids.is_empty() || reduced.asks.is_empty() {
+                    continue;
+                }
                 let tx_pool_locked = tx_pool.read().await;
-                if tx_pool_locked.len() == 0 {
+                if tx_pool_locked.is_empty() {
                     continue;
                 }
-                let mut tx_pool_iter = tx_pool_locked.iter();
-                let mut futures = vec![];
-                // build the Summary
-                let bids_out: Vec<Level> = aggregated_orderbook_reduced
-                    .bids
-                    .into_values()
-                    .rev()
-                    .collect();
-                let asks_out: Vec<Level> =
-                    aggregated_orderbook_reduced.asks.into_values().collect();
 
-                let summary = Summary {
-                    spread: asks_out[0].price - bids_out[0].price,
-                    bids: bids_out,
-                    asks: asks_out,
-                };
+                // group the clients by the view of the book they requested, each view is rendered
+                // only once.
+                let mut views: HashMap<&View, Vec<(&Uuid, &Subscriber)>> = HashMap::new();
+                for (id, subscriber) in tx_pool_locked.iter() {
+                    views
+                        .entry(&subscriber.view)
+                        .or_default()
+                        .push((id, subscriber));
+                }
+                last_published.retain(|view, _| views.contains_key(view));
 
-                // push out the aggregated orderbook to all client tx streams
-                while let Some((_, tx)) = tx_pool_iter.next() {
-                    futures.push(tx.send(Ok(summary.clone())));
+                let mut dropped = vec![];
+                for (view, subscribers) in views {
+                    let mut summary = if view.is_default() {
+                        build_summary(aggregated_orderbook_reduced.clone())
+                    } else {
+                        view.render(&books, conf.depth)
+                    };
+                    if let Some(params) = &view.analytics {
+                        summary.analytics = Some(analytics::compute(&books, params));
+                    }
+
+                    // Exchanges often send messages that only change levels beyond the configured
+                    // depth. If the rendered book is unchanged only clients that have not yet
+                    // received it are served.
+                    let (last, served) = last_published
+                        .entry(view.clone())
+                        .or_insert_with(|| (Summary::default(), HashSet::new()));
+                    if *last == summary {
+                        let suppressed = Metrics::increment(&metrics.suppressed_publishes);
+                        debug!(
+                            "Aggregated orderbook unchanged, publish suppressed ({} in total).",
+                            suppressed
+                        );
+                    } else {
+                        *last = summary;
+                        served.clear();
+                    }
+                    let subscribers: Vec<_> = subscribers
+                        .into_iter()
+                        .filter(|(id, _)| !served.contains(*id))
+                        .collect();
+                    served.extend(subscribers.iter().map(|(id, _)| **id));
+                    dropped.extend(publish(
+                        last,
+                        subscribers.into_iter(),
+                        policy,
+                        max_client_lag,
+                        metrics,
+                    ));
                 }
+                drop(tx_pool_locked);
 
-                for r in futures::future::join_all(futures).await {
-                    if let Err(_item) = r {
-                        error!("Error sending aggregated orderbook Summary");
+                if !dropped.is_empty() {
+                    let mut tx_pool_locked = tx_pool.write().await;
+                    for id in dropped {
+                        tx_pool_locked.remove(&id);
                     }
                 }
             }
@@ -135,3 +287,281 @@ pub async fn aggregate_orderbooks(
     error!("Input stream closed unexpectedly!");
     Ok(())
 }
+
+// Build the Summary pushed to clients from the reduced aggregated orderbook.
+pub fn build_summary(orderbook: Orderbook) -> Summary {
+    let bids_out: Vec<Level> = orderbook.bids.into_values().rev().collect();
+    let asks_out: Vec<Level> = orderbook.asks.into_values().collect();
+
+    let spread = match (asks_out.first(), bids_out.first()) {
+        (Some(ask), Some(bid)) => ask.price - bid.price,
+        _ => 0.0,
+    };
+    Summary {
+        spread,
+        bids: bids_out,
+        asks: asks_out,
+        ..Default::default()
+    }
+}
+
+// Push out the aggregated orderbook to the given clients without ever waiting on a single one of
+// them. Returns the ids of the clients that must be removed from the producer pool.
+pub fn publish<'a>(
+    summary: &Summary,
+    subscribers: impl Iterator<Item = (&'a Uuid, &'a Subscriber)>,
+    policy: SlowClientPolicy,
+    max_client_lag: u64,
+    metrics: &Metrics,
+) -> Vec<Uuid> {
+    let mut dropped = vec![];
+    for (id, subscriber) in subscribers {
+        match subscriber.deliver(summary) {
+            Delivery::Sent => {}
+            Delivery::Conflated(lag) => {
+                Metrics::increment(&metrics.conflated_publishes);
+                if policy == SlowClientPolicy::Disconnect && lag > max_client_lag {
+                    warn!(
+                        "gRPC client {} lagged {} summaries behind, disconnecting.",
+                        id, lag
+                    );
+                    subscriber.evict(format!(
+                        "client lagged {} summaries behind the aggregated orderbook",
+                        lag
+                    ));
+                    Metrics::increment(&metrics.evicted_clients);
+                    dropped.push(*id);
+                }
+            }
+            Delivery::Closed => {
+                error!("Error sending aggregated orderbook Summary");
+                dropped.push(*id);
+            }
+        }
+    }
+    dropped
+}
+
+// Mid price of a book, if both of its sides are quoted.
+fn mid(orderbook: &Orderbook) -> Option<f64> {
+    let bid = orderbook.bids.values().next_back()?;
+    let ask = orderbook.asks.values().next()?;
+    Some((bid.price + ask.price) / 2.0)
+}
+
+async fn publish_candles(history: &Mutex<CandleHistory>, closed: Vec<Candle>) {
+    if closed.is_empty() {
+        return;
+    }
+    let mut history = history.lock().await;
+    for candle in closed {
+        history.publish(candle);
+    }
+}
+
+// Publish the best bid and offer of the aggregated book, only when it changed. Returns whether it
+// did.
+pub fn publish_bbo(bbo: &watch::Sender<Bbo>, orderbook: &Orderbook) -> bool {
+    let bid = orderbook.bids.values().next_back().cloned();
+    let ask = orderbook.asks.values().next().cloned();
+    let next = Bbo {
+        spread: match (&bid, &ask) {
+            (Some(bid), Some(ask)) => ask.price - bid.price,
+            _ => 0.0,
+        },
+        bid,
+        ask,
+    };
+    bbo.send_if_modified(|current| {
+        let changed = *current != next;
+        if changed {
+            *current = next;
+        }
+        changed
+    })
+}
+
+// Stream the best bid and offer to a gRPC client, starting with the current one. A client that
+// does not keep up only receives the latest one.
+pub fn bbo_stream(mut rx: watch::Receiver<Bbo>) -> impl Stream<Item = Result<Bbo, Status>> {
+    stream! {
+        loop {
+            let bbo = rx.borrow_and_update().clone();
+            if bbo.bid.is_some() || bbo.ask.is_some() {
+                yield Ok(bbo);
+            }
+            if rx.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use tokio::{
+        sync::{mpsc, watch, RwLock},
+        time::Duration,
+    };
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use super::{aggregate_orderbooks, bbo_stream, publish, publish_bbo, Outputs};
+    use crate::{
+        config::{self, SlowClientPolicy},
+        definitions::{MarketData, Orderbook, Orderbooks},
+        metrics::Metrics,
+        orderbook::{Bbo, CandleRequest, Level, Summary},
+        subscriber::Subscriber,
+        view::View,
+    };
+
+    fn level(exchange: &str, price: i64, amount: f64) -> (Decimal, Level) {
+        let level = Level {
+            exchange: exchange.into(),
+            price: price as f64,
+            amount,
+            ..Default::default()
+        };
+        (Decimal::from(price), level)
+    }
+
+    #[tokio::test]
+    async fn bbo_only_published_on_change() {
+        let (tx, rx) = watch::channel(Bbo::default());
+        let mut stream = Box::pin(bbo_stream(rx));
+        let mut orderbook = Orderbook::new();
+        orderbook.bids = [level("binance", 99, 1.0), level("bitstamp", 98, 1.0)].into();
+        orderbook.asks = [level("bitstamp", 101, 2.0), level("binance", 102, 1.0)].into();
+        assert!(publish_bbo(&tx, &orderbook));
+
+        let bbo = stream.next().await.unwrap().unwrap();
+        assert_eq!(bbo.bid.unwrap().exchange, "binance");
+        assert_eq!(bbo.ask.unwrap().amount, 2.0);
+        assert_eq!(bbo.spread, 2.0);
+
+        // a deeper level moving does not change the bbo.
+        orderbook.bids.extend([level("bitstamp", 97, 3.0)]);
+        assert!(!publish_bbo(&tx, &orderbook));
+        orderbook.asks.extend([level("binance", 100, 1.0)]);
+        assert!(publish_bbo(&tx, &orderbook));
+        assert_eq!(stream.next().await.unwrap().unwrap().spread, 1.0);
+    }
+
+    #[tokio::test]
+    async fn unchanged_book_is_not_republished() {
+        let conf = config::Server::builder().depth(1).build();
+        let (tx, mut rx) = mpsc::channel(16);
+        let tx_pool = RwLock::new(HashMap::new());
+        let (subscriber, summaries) = Subscriber::new(16, None, View::default());
+        tx_pool.write().await.insert(Uuid::new_v4(), subscriber);
+
+        let book = |bids: [i64; 2], asks: [i64; 2]| {
+            let mut orderbook = Orderbook::new();
+            orderbook.bids = bids.map(|price| level("binance", price, 1.0)).into();
+            orderbook.asks = asks.map(|price| level("binance", price, 1.0)).into();
+            MarketData::Book(Orderbooks::Binance(orderbook))
+        };
+        // only the levels beyond the depth change, then the best bid.
+        for orderbooks in [
+            book([99, 97], [101, 103]),
+            book([99, 98], [101, 102]),
+            book([100, 98], [101, 102]),
+        ] {
+            tx.send(Ok(orderbooks)).await.unwrap();
+        }
+        drop(tx);
+        let (outputs, metrics) = (Outputs::new(), Metrics::new());
+        aggregate_orderbooks(&conf, &mut rx, &tx_pool, &outputs, None, None, &metrics)
+            .await
+            .unwrap();
+        drop(tx_pool);
+
+        let published: Vec<f64> = summaries
+            .map(|summary| summary.unwrap().bids[0].price)
+            .collect()
+            .await;
+        assert_eq!(published, vec![99.0, 100.0]);
+        assert_eq!(metrics.snapshot().suppressed_publishes, 1);
+    }
+
+    #[tokio::test]
+    async fn mid_bars_are_timed_by_the_books() {
+        const SECOND: u64 = 1_000_000;
+        let conf = config::Server::builder().build();
+        let (tx, mut rx) = mpsc::channel(16);
+        let start = 1_661_585_400 * SECOND;
+        for (received, mid) in [
+            (start, 100),
+            (start + SECOND / 2, 102),
+            (start + 3 * SECOND, 104),
+        ] {
+            let mut orderbook = Orderbook::new();
+            orderbook.bids = [level("binance", mid - 1, 1.0)].into();
+            orderbook.asks = [level("binance", mid + 1, 1.0)].into();
+            orderbook.received = received;
+            tx.send(Ok(MarketData::Book(Orderbooks::Binance(orderbook))))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+        let (outputs, metrics) = (Outputs::new(), Metrics::new());
+        let tx_pool = RwLock::new(HashMap::new());
+        aggregate_orderbooks(&conf, &mut rx, &tx_pool, &outputs, None, None, &metrics)
+            .await
+            .unwrap();
+
+        // the bar of the first second is completed by the book received 3 seconds later.
+        let req = CandleRequest {
+            interval: 1,
+            exchange: "binance".into(),
+        };
+        let (bars, _) = outputs.candles.lock().await.subscribe(&req);
+        assert_eq!(bars.len(), 1);
+        assert_eq!(
+            (bars[0].open_time, bars[0].open, bars[0].close),
+            (start, 100.0, 102.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn throttled_client_that_stops_reading_is_disconnected() {
+        let interval = Duration::from_secs(60);
+        let metrics = Metrics::new();
+        let publish_to = |id: &Uuid, subscriber: &Subscriber, spread| {
+            let summary = Summary {
+                spread,
+                ..Default::default()
+            };
+            let subscribers = [(id, subscriber)].into_iter();
+            publish(
+                &summary,
+                subscribers,
+                SlowClientPolicy::Disconnect,
+                2,
+                &metrics,
+            )
+        };
+
+        // the client takes a summary and is not lagging while it waits out its interval.
+        let id = Uuid::new_v4();
+        let (subscriber, mut summaries) = Subscriber::new(16, Some(interval), View::default());
+        assert!(publish_to(&id, &subscriber, 1.0).is_empty());
+        assert_eq!(summaries.next().await.unwrap().unwrap().spread, 1.0);
+        for spread in 2..10 {
+            assert!(publish_to(&id, &subscriber, spread as f64).is_empty());
+        }
+        assert_eq!(metrics.evicted_clients.load(Ordering::Relaxed), 0);
+
+        // a client that does not take the summaries once its interval is over is evicted.
+        let (subscriber, _summaries) = Subscriber::new(16, Some(interval), View::default());
+        for spread in 1..4 {
+            assert!(publish_to(&id, &subscriber, spread as f64).is_empty());
+        }
+        assert_eq!(publish_to(&id, &subscriber, 4.0), vec![id]);
+        assert_eq!(metrics.evicted_clients.load(Ordering::Relaxed), 1);
+    }
+}
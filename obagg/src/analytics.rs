@@ -0,0 +1,165 @@
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    definitions::Orderbook,
+    orderbook::{Analytics, AnalyticsRequest, DepthWithin, ExchangePressure},
+};
+
+const DEFAULT_LEVELS: usize = 5;
+const DEFAULT_DEPTH_BPS: [u32; 2] = [10, 50];
+
+// Analytics requested by a client, part of its view.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AnalyticsParams {
+    pub levels: usize,
+    pub depth_bps: Vec<u32>,
+}
+
+impl From<&AnalyticsRequest> for AnalyticsParams {
+    fn from(req: &AnalyticsRequest) -> Self {
+        AnalyticsParams {
+            levels: match req.levels {
+                0 => DEFAULT_LEVELS,
+                levels => levels as usize,
+            },
+            depth_bps: match req.depth_bps.is_empty() {
+                true => DEFAULT_DEPTH_BPS.to_vec(),
+                false => req.depth_bps.clone(),
+            },
+        }
+    }
+}
+
+// Amounts quoted at each price, per exchange.
+type Ladder<'a> = BTreeMap<&'a str, BTreeMap<Decimal, f64>>;
+
+fn imbalance(bid_amount: f64, ask_amount: f64) -> f64 {
+    match bid_amount + ask_amount {
+        total if total > 0.0 => (bid_amount - ask_amount) / total,
+        _ => 0.0,
+    }
+}
+
+// Compute the analytics of the book made of the per-exchange orderbooks.
+pub fn compute(books: &[&Orderbook], params: &AnalyticsParams) -> Analytics {
+    let mut bids: Ladder = BTreeMap::new();
+    let mut asks: Ladder = BTreeMap::new();
+    for book in books {
+        for (ladder, levels) in [(&mut bids, &book.bids), (&mut asks, &book.asks)] {
+            for (price, level) in levels {
+                *ladder
+                    .entry(level.exchange.as_str())
+                    .or_default()
+                    .entry(*price)
+                    .or_default() += level.amount;
+            }
+        }
+    }
+    let consolidate = |ladder: &Ladder| {
+        let mut consolidated: BTreeMap<Decimal, f64> = BTreeMap::new();
+        for (price, amount) in ladder.values().flatten() {
+            *consolidated.entry(*price).or_default() += amount;
+        }
+        consolidated
+    };
+    let (all_bids, all_asks) = (consolidate(&bids), consolidate(&asks));
+    let (Some((best_bid, bid_amount)), Some((best_ask, ask_amount))) =
+        (all_bids.iter().next_back(), all_asks.iter().next())
+    else {
+        return Analytics::default();
+    };
+    let mid = (best_bid + best_ask) / Decimal::TWO;
+    let microprice = (best_bid * Decimal::try_from(*ask_amount).unwrap_or_default()
+        + best_ask * Decimal::try_from(*bid_amount).unwrap_or_default())
+    .checked_div(Decimal::try_from(bid_amount + ask_amount).unwrap_or_default())
+    .unwrap_or(mid);
+
+    let top = |levels: &mut dyn Iterator<Item = (&Decimal, &f64)>| -> f64 {
+        levels.take(params.levels).map(|(_, amount)| amount).sum()
+    };
+    let depth = params
+        .depth_bps
+        .iter()
+        .map(|&bps| {
+            let distance = mid * Decimal::from(bps) / Decimal::from(10_000);
+            DepthWithin {
+                bps,
+                bid_amount: all_bids.range(mid - distance..).map(|(_, a)| a).sum(),
+                ask_amount: all_asks.range(..=mid + distance).map(|(_, a)| a).sum(),
+            }
+        })
+        .collect();
+    let exchanges: BTreeSet<&str> = bids.keys().chain(asks.keys()).copied().collect();
+    let pressure = exchanges
+        .into_iter()
+        .map(|exchange| {
+            let bid_amount = bids.get(exchange).map_or(0.0, |l| top(&mut l.iter().rev()));
+            let ask_amount = asks.get(exchange).map_or(0.0, |l| top(&mut l.iter()));
+            ExchangePressure {
+                exchange: exchange.into(),
+                bid_amount,
+                ask_amount,
+                pressure: imbalance(bid_amount, ask_amount),
+            }
+        })
+        .collect();
+
+    Analytics {
+        mid: mid.to_f64().unwrap_or(0.0),
+        microprice: microprice.to_f64().unwrap_or(0.0),
+        imbalance: imbalance(top(&mut all_bids.iter().rev()), top(&mut all_asks.iter())),
+        depth,
+        pressure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+    use super::{compute, AnalyticsParams};
+    use crate::{definitions::Orderbook, orderbook::Level};
+
+    fn book(exchange: &str, bids: &[(f64, f64)], asks: &[(f64, f64)]) -> Orderbook {
+        let level = |&(price, amount): &(f64, f64)| {
+            let level = Level {
+                exchange: exchange.into(),
+                price,
+                amount,
+                ..Default::default()
+            };
+            (Decimal::from_f64(price).unwrap(), level)
+        };
+        let mut book = Orderbook::new();
+        book.bids = bids.iter().map(level).collect();
+        book.asks = asks.iter().map(level).collect();
+        book
+    }
+
+    #[test]
+    fn analytics_of_merged_books() {
+        let binance = book("binance", &[(99.0, 3.0), (98.0, 1.0)], &[(101.0, 1.0)]);
+        let bitstamp = book("bitstamp", &[(99.0, 1.0)], &[(102.0, 4.0)]);
+        let params = AnalyticsParams {
+            levels: 2,
+            depth_bps: vec![100, 300],
+        };
+        let analytics = compute(&[&binance, &bitstamp], &params);
+
+        assert_eq!(analytics.mid, 100.0);
+        // 4 bid at 99 against 1 ask at 101 pushes the microprice towards the ask.
+        assert_eq!(analytics.microprice, 100.6);
+        // top 2 levels: 5 bid against 5 ask.
+        assert_eq!(analytics.imbalance, 0.0);
+        assert_eq!(analytics.depth[0].bps, 100);
+        assert_eq!(analytics.depth[0].bid_amount, 4.0);
+        assert_eq!(analytics.depth[0].ask_amount, 1.0);
+        assert_eq!(analytics.depth[1].bid_amount, 5.0);
+        assert_eq!(analytics.depth[1].ask_amount, 5.0);
+        assert_eq!(analytics.pressure.len(), 2);
+        assert_eq!(analytics.pressure[0].exchange, "binance");
+        assert_eq!(analytics.pressure[0].pressure, 0.6);
+        assert_eq!(analytics.pressure[1].pressure, -0.6);
+    }
+}
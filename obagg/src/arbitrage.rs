@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+use crate::{config, definitions::Orderbook, orderbook::ArbitrageOpportunity};
+
+// Tracks the crossed books between every pair of exchanges from their cached orderbooks.
+#[derive(Default)]
+pub struct Detector {
+    // opportunities currently open, keyed by buy and sell exchange, with the time they opened.
+    open: HashMap<(String, String), (Instant, ArbitrageOpportunity)>,
+}
+
+impl Detector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Compare the books of every pair of exchange labels and return the events of the
+    // opportunities that opened, changed or closed. Books converted from another quote, e.g.
+    // binance:usdt, pay the taker fee of their exchange.
+    pub fn update(
+        &mut self,
+        books: &[(&str, &Orderbook)],
+        conf: &config::Server,
+        now: Instant,
+    ) -> Vec<ArbitrageOpportunity> {
+        let fee = |label: &str| {
+            let exchange = label.split(':').next().unwrap_or(label);
+            conf.exchanges
+                .get(exchange)
+                .and_then(|e| e.taker_fee)
+                .unwrap_or(0.0)
+        };
+        let mut events = vec![];
+        for &(buy_exchange, buy) in books {
+            for &(sell_exchange, sell) in books {
+                if buy_exchange == sell_exchange {
+                    continue;
+                }
+                let key = (buy_exchange.to_string(), sell_exchange.to_string());
+                let Some(mut opportunity) = cross(buy, sell, fee(buy_exchange), fee(sell_exchange))
+                else {
+                    if let Some((opened, mut opportunity)) = self.open.remove(&key) {
+                        opportunity.duration = (now - opened).try_into().ok();
+                        opportunity.closed = true;
+                        events.push(opportunity);
+                    }
+                    continue;
+                };
+                opportunity.buy_exchange = buy_exchange.into();
+                opportunity.sell_exchange = sell_exchange.into();
+                let (opened, last) = self
+                    .open
+                    .entry(key)
+                    .or_insert_with(|| (now, ArbitrageOpportunity::default()));
+                opportunity.duration = (now - *opened).try_into().ok();
+                // only report opportunities whose prices or size changed.
+                let unchanged = last.buy_price == opportunity.buy_price
+                    && last.sell_price == opportunity.sell_price
+                    && last.size == opportunity.size
+                    && last.edge == opportunity.edge;
+                if !unchanged {
+                    *last = opportunity.clone();
+                    events.push(opportunity);
+                }
+            }
+        }
+        // opportunities of a book that is no longer compared are closed.
+        let compared = |label: &str| books.iter().any(|&(exchange, _)| exchange == label);
+        let gone: Vec<_> = self
+            .open
+            .keys()
+            .filter(|(buy, sell)| !compared(buy) || !compared(sell))
+            .cloned()
+            .collect();
+        for key in gone {
+            let (opened, mut opportunity) = self.open.remove(&key).unwrap();
+            opportunity.duration = (now - opened).try_into().ok();
+            opportunity.closed = true;
+            events.push(opportunity);
+        }
+        events
+    }
+}
+
+// Walk the asks of the buy book and the bids of the sell book while they are crossed.
+fn cross(
+    buy: &Orderbook,
+    sell: &Orderbook,
+    buy_fee: f64,
+    sell_fee: f64,
+) -> Option<ArbitrageOpportunity> {
+    let mut asks = buy.asks.values().map(|l| (l.price, l.amount));
+    let mut bids = sell.bids.values().rev().map(|l| (l.price, l.amount));
+    let (mut ask, mut bid) = (asks.next()?, bids.next()?);
+    if bid.0 <= ask.0 {
+        return None;
+    }
+    let mut opportunity = ArbitrageOpportunity {
+        buy_price: ask.0,
+        sell_price: bid.0,
+        ..Default::default()
+    };
+    while bid.0 > ask.0 {
+        let size = ask.1.min(bid.1);
+        opportunity.size += size;
+        opportunity.edge += size * (bid.0 * (1.0 - sell_fee) - ask.0 * (1.0 + buy_fee));
+        ask.1 -= size;
+        bid.1 -= size;
+        if ask.1 <= 0.0 {
+            match asks.next() {
+                Some(next) => ask = next,
+                None => break,
+            }
+        }
+        if bid.1 <= 0.0 {
+            match bids.next() {
+                Some(next) => bid = next,
+                None => break,
+            }
+        }
+    }
+    Some(opportunity)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::{prelude::FromPrimitive, Decimal};
+    use tokio::time::{Duration, Instant};
+
+    use super::Detector;
+    use crate::{config, definitions::Orderbook, orderbook::Level};
+
+    fn book(bids: &[(f64, f64)], asks: &[(f64, f64)]) -> Orderbook {
+        let level = |&(price, amount): &(f64, f64)| {
+            let level = Level {
+                price,
+                amount,
+                ..Default::default()
+            };
+            (Decimal::from_f64(price).unwrap(), level)
+        };
+        let mut book = Orderbook::new();
+        book.bids = bids.iter().map(level).collect();
+        book.asks = asks.iter().map(level).collect();
+        book
+    }
+
+    #[test]
+    fn crossed_books_open_and_close() {
+        let conf = config::Server::builder()
+            .with(|conf| conf.exchanges.binance.taker_fee = Some(0.001))
+            .build();
+        let mut detector = Detector::new();
+        let start = Instant::now();
+        let binance = book(&[(99.0, 1.0)], &[(100.0, 1.0), (100.5, 2.0)]);
+        let bitstamp = book(&[(101.0, 1.5), (100.8, 1.0)], &[(102.0, 1.0)]);
+
+        let books = [("binance", &binance), ("bitstamp", &bitstamp)];
+        let events = detector.update(&books, &conf, start);
+        // buy 1 at 100 and 1.5 at 100.5 on binance, sell 1.5 at 101 and 1 at 100.8 on bitstamp.
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].buy_exchange, "binance");
+        assert_eq!(events[0].sell_exchange, "bitstamp");
+        assert_eq!(events[0].buy_price, 100.0);
+        assert_eq!(events[0].sell_price, 101.0);
+        assert_eq!(events[0].size, 2.5);
+        assert!((events[0].edge - (0.9 + 0.5 * 0.3995 + 0.1995)).abs() < 1e-9);
+
+        // unchanged books do not emit again.
+        let later = start + Duration::from_millis(250);
+        assert!(detector.update(&books, &conf, later).is_empty());
+
+        let uncrossed = book(&[(99.0, 1.0)], &[(102.0, 1.0)]);
+        let events = detector.update(
+            &[("binance", &uncrossed), ("bitstamp", &bitstamp)],
+            &conf,
+            later,
+        );
+        assert_eq!(events.len(), 1);
+        assert!(events[0].closed);
+        assert_eq!(events[0].duration.clone().unwrap().nanos, 250_000_000);
+    }
+
+    #[test]
+    fn every_pair_of_books_is_compared() {
+        let conf = config::Server::builder()
+            .with(|conf| conf.exchanges.binance.taker_fee = Some(0.001))
+            .build();
+        let mut detector = Detector::new();
+        let start = Instant::now();
+        let binance = book(&[(99.0, 1.0)], &[(100.0, 1.0)]);
+        let bitstamp = book(&[(99.5, 1.0)], &[(100.5, 1.0)]);
+        // the btcusdt book of binance converted into the ticker quote.
+        let converted = book(&[(101.0, 2.0)], &[(102.0, 1.0)]);
+
+        let books = [
+            ("binance", &binance),
+            ("bitstamp", &bitstamp),
+            ("binance:usdt", &converted),
+        ];
+        let mut events = detector.update(&books, &conf, start);
+        events.sort_by(|a, b| a.buy_exchange.cmp(&b.buy_exchange));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].buy_exchange, "binance");
+        assert_eq!(events[0].sell_exchange, "binance:usdt");
+        // the converted book pays the taker fee of binance on both legs.
+        assert!((events[0].edge - (101.0 * 0.999 - 100.0 * 1.001)).abs() < 1e-9);
+        assert_eq!(events[1].buy_exchange, "bitstamp");
+        assert_eq!(events[1].sell_exchange, "binance:usdt");
+        assert!((events[1].edge - (101.0 * 0.999 - 100.5)).abs() < 1e-9);
+
+        // the opportunities of a book left out are closed.
+        let later = start + Duration::from_millis(100);
+        let events = detector.update(&books[..2], &conf, later);
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.closed));
+    }
+}
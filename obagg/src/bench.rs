@@ -0,0 +1,96 @@
+// Entry points for the benchmarks under benches/, which cannot reach the crate internals they
+// measure. Not part of the public API.
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+use crate::{
+    aggregator,
+    config::SlowClientPolicy,
+    definitions::Orderbook,
+    metrics::Metrics,
+    orderbook::{Level, Summary},
+    subscriber::{Subscriber, SummaryStream},
+    view::View,
+};
+
+// A merged book of the two exchanges holding depth levels per side.
+fn orderbook(depth: usize) -> Orderbook {
+    let mut orderbook = Orderbook::new();
+    for i in 0..depth as i64 {
+        for (exchange, amount) in [("binance", 1.5), ("bitstamp", 2.5)] {
+            let bid = Decimal::new(25_000 - i, 7);
+            let ask = Decimal::new(25_001 + i, 7);
+            orderbook.bids.insert(
+                bid * Decimal::new(10, 0) + Decimal::from_f64_retain(amount).unwrap(),
+                Level {
+                    exchange: exchange.into(),
+                    price: 0.0025 - i as f64 * 1e-7,
+                    amount,
+                    ..Default::default()
+                },
+            );
+            orderbook.asks.insert(
+                ask * Decimal::new(10, 0) + Decimal::from_f64_retain(amount).unwrap(),
+                Level {
+                    exchange: exchange.into(),
+                    price: 0.0025001 + i as f64 * 1e-7,
+                    amount,
+                    ..Default::default()
+                },
+            );
+        }
+    }
+    orderbook.reduce(depth)
+}
+
+// The check the aggregator runs on every update to find that the top-N levels did not change.
+pub struct ChangeDetection {
+    last: Summary,
+    next: Orderbook,
+}
+
+impl ChangeDetection {
+    pub fn new(depth: usize) -> Self {
+        ChangeDetection {
+            last: aggregator::build_summary(orderbook(depth)),
+            next: orderbook(depth),
+        }
+    }
+
+    pub fn unchanged(&self) -> bool {
+        aggregator::build_summary(self.next.clone()) == self.last
+    }
+}
+
+// The fan-out of a summary to the connected clients that the change detection avoids.
+pub struct FanOut {
+    summary: Summary,
+    subscribers: Vec<(Uuid, (Subscriber, SummaryStream))>,
+    metrics: Metrics,
+}
+
+impl FanOut {
+    pub fn new(depth: usize, clients: usize) -> Self {
+        FanOut {
+            summary: aggregator::build_summary(orderbook(depth)),
+            subscribers: (0..clients)
+                .map(|_| (Uuid::new_v4(), Subscriber::new(1024, None, View::default())))
+                .collect(),
+            metrics: Metrics::new(),
+        }
+    }
+
+    // Returns self so that dropping the clients is left out of the measurement.
+    pub fn publish(self) -> Self {
+        aggregator::publish(
+            &self.summary,
+            self.subscribers
+                .iter()
+                .map(|(id, (subscriber, _))| (id, subscriber)),
+            SlowClientPolicy::Conflate,
+            100,
+            &self.metrics,
+        );
+        self
+    }
+}
@@ -1,11 +1,17 @@
 mod log;
 use ::log::error;
+use obagg::config;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct OBAggregatorOpt {
     #[structopt(long = "--no-syslog")]
     disable_syslog: bool,
+    /// Configuration file, defaults to the file pointed to by AGGREGATED_ORDERBOOK_CONFIG. Keys
+    /// are overridden by OBAGG__<KEY> variables, e.g. OBAGG__DEPTH=50.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
     #[structopt(subcommand)]
     subcommand: Subcommand,
 }
@@ -14,25 +20,87 @@ struct OBAggregatorOpt {
 enum Subcommand {
     Grpc,
     Client,
+    /// Replay recorded exchange frames through the aggregator.
+    Replay {
+        /// Capture file, or directory of capture files, to replay.
+        #[structopt(long, parse(from_os_str))]
+        input: PathBuf,
+        /// Pace relative to the recording, 0 replays as fast as possible.
+        #[structopt(long, default_value = "1")]
+        speed: f64,
+        /// Write the summaries to this file instead of serving them over gRPC.
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Validate the configuration and report every problem found, with its line.
+    CheckConfig,
     Version,
 }
 
+fn load_config(path: Option<&Path>) -> Option<config::Server> {
+    match config::Server::load(path) {
+        Ok(conf) => Some(conf),
+        Err(e) => {
+            error!("Error loading configuration : {}", e);
+            None
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let opt = OBAggregatorOpt::from_args();
     match opt.subcommand {
         Subcommand::Grpc => {
             log::init("obagg-server".to_string(), opt.disable_syslog);
-            if let Err(e) = obagg::server(obagg::config::read_config()).await {
+            let Some(conf) = load_config(opt.config.as_deref()) else {
+                return;
+            };
+            if let Err(e) = obagg::server(conf).await {
                 error!("Error returned from Server : {}", e);
             }
         }
         Subcommand::Client => {
             log::init("obagg-client".to_string(), opt.disable_syslog);
-            if let Err(e) = obagg::client(obagg::config::read_config()).await {
+            let Some(conf) = load_config(opt.config.as_deref()) else {
+                return;
+            };
+            if let Err(e) = obagg::client(conf).await {
                 error!("Error returned from Server : {}", e);
             }
         }
+        Subcommand::Replay {
+            input,
+            speed,
+            output,
+        } => {
+            log::init("obagg-replay".to_string(), opt.disable_syslog);
+            let Some(conf) = load_config(opt.config.as_deref()) else {
+                return;
+            };
+            if let Err(e) = obagg::replay(conf, &input, speed, output.as_deref()).await {
+                error!("Error returned from Replay : {}", e);
+            }
+        }
+        Subcommand::CheckConfig => {
+            let problems = config::config_path(opt.config.as_deref())
+                .map_err(|e| e.into())
+                .and_then(|path| obagg::check_config(&path, std::env::vars()));
+            match problems {
+                Ok(problems) if problems.is_empty() => println!("Configuration is valid."),
+                Ok(problems) => {
+                    for problem in &problems {
+                        eprintln!("{problem}");
+                    }
+                    eprintln!("{} problem(s) found.", problems.len());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
         Subcommand::Version => {
             println!("Obagg Orderbook Aggregator {}", env!("CARGO_PKG_VERSION"));
         }
@@ -1,30 +1,32 @@
 use futures::StreamExt;
 use log::{debug, error, info};
-use serde_json;
-use std::{error::Error, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use std::error::Error;
+use tokio::sync::Mutex;
 use tokio_tungstenite::connect_async;
-use tonic::Status;
 
 use crate::{
+    capture::frame::Kind,
     config,
     definitions::{
-        BinanceOrderbookMessage, BinanceOrderbookUpdateMessage, ExchangeOrderbookLevel, Orderbook,
-        Orderbooks,
+        BinanceOrderbookMessage, BinanceOrderbookUpdateMessage, BinanceTradeMessage,
+        ExchangeOrderbookLevel, FeedEvent, Orderbook,
     },
+    feed::FeedSender,
+    orderbook::Trade,
     utils,
 };
 
 // For depths 20 and under we employ the reduced orderbook stream.
 pub async fn consume_reduced_orderbooks(
     conf: &config::Server,
-    tx: &mpsc::Sender<Result<Orderbooks, Status>>,
+    ticker: &str,
+    tx: &FeedSender,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Binance Collector Started, attempting to connect to websocket server...");
-    let base = url::Url::parse(&conf.exchanges.binance.websocket.as_str())?;
+    let base = url::Url::parse(conf.exchanges.binance.websocket.as_str())?;
     let channel = format!(
         "/ws/{}@depth{}@{}",
-        conf.ticker,
+        ticker,
         conf.depth,
         conf.exchanges
             .binance
@@ -44,9 +46,9 @@ pub async fn consume_reduced_orderbooks(
     // now we handle incoming messages
     let read_future = Box::pin({
         read.for_each(|message| async {
-            let mut orderbook = Orderbook::new();
             match message {
                 Ok(message) => {
+                    tx.record_message(&message);
                     let msg = match utils::handle_message(message) {
                         Ok(s) => s,
                         Err(e) => {
@@ -54,23 +56,9 @@ pub async fn consume_reduced_orderbooks(
                             return;
                         }
                     };
-                    match serde_json::from_str::<BinanceOrderbookMessage>(&msg) {
-                        Ok(orderbook_message) => {
-                            for bid in orderbook_message.bids {
-                                orderbook.bids.insert(
-                                    bid.price(),
-                                    ExchangeOrderbookLevel::Binance(bid).into(),
-                                );
-                            }
-                            for ask in orderbook_message.asks {
-                                orderbook.asks.insert(
-                                    ask.price(),
-                                    ExchangeOrderbookLevel::Binance(ask).into(),
-                                );
-                            }
-                            if let Err(_item) =
-                                tx.send(Result::<Orderbooks, Status>::Ok(Orderbooks::Binance(orderbook))).await
-                            {
+                    match parse_reduced_orderbook(&msg) {
+                        Ok(orderbook) => {
+                            if let Err(_item) = tx.send(FeedEvent::Orderbook(orderbook)).await {
                                 error!("Error sending binance orderbook item.");
                             };
                         }
@@ -92,6 +80,53 @@ pub async fn consume_reduced_orderbooks(
     Ok(())
 }
 
+// Consume the raw trade stream of the ticker.
+pub async fn consume_trades(
+    conf: &config::Server,
+    ticker: &str,
+    tx: &FeedSender,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Binance Trade Collector Started, attempting to connect to websocket server...");
+    let base = url::Url::parse(conf.exchanges.binance.websocket.as_str())?;
+    let url = base.join(format!("/ws/{}@trade", ticker).as_str())?;
+    let (ws_stream, _) = connect_async(url).await?;
+    info!("Binance trade WebSocket handshake has been successfully completed.");
+
+    let (write, read) = ws_stream.split();
+    let ping_future = utils::ping_sender(write, conf.exchanges.binance.ping_period);
+    let read_future = read.for_each(|message| async {
+        match message {
+            Ok(message) => {
+                tx.record_message(&message);
+                let msg = match utils::handle_message(message) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        debug!("{}", e);
+                        return;
+                    }
+                };
+                match parse_trade(&msg) {
+                    Ok(trade) => {
+                        if let Err(_item) = tx.send(FeedEvent::Trade(trade)).await {
+                            error!("Error sending binance trade item.");
+                        };
+                    }
+                    Err(err) => {
+                        debug!("Message is not a Trade message. {}: msg {}", err, msg);
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Data was not message!");
+                error!("{}", err);
+            }
+        }
+    });
+    futures::future::select(Box::pin(read_future), Box::pin(ping_future)).await;
+    error!("Websocket failed and closed!");
+    Ok(())
+}
+
 // For depths over 20 we must employ the full orderbook websocket channel.
 // In this case we open a websocket connection and process the update messages into a locally stored
 // orderbook. The following set of rules are applied:
@@ -109,15 +144,15 @@ pub async fn consume_reduced_orderbooks(
 //     that this rule was not elaborated in the binance documentation.)
 pub async fn consume_orderbooks(
     conf: &config::Server,
-    tx: &mpsc::Sender<Result<Orderbooks, Status>>,
+    ticker: &str,
+    tx: &FeedSender,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     // Binance requires that the ticker and params be specified in the url. First we must construct
     // the url.
-    let orderbook_arc = Arc::new(Mutex::new(Orderbook::new()));
-    let ws_base = url::Url::parse(&conf.exchanges.binance.websocket.as_str())?;
+    let ws_base = url::Url::parse(conf.exchanges.binance.websocket.as_str())?;
     let ws_channel = format!(
         "/ws/{}@depth@{}",
-        &conf.ticker,
+        ticker,
         conf.exchanges
             .binance
             .period
@@ -132,11 +167,9 @@ pub async fn consume_orderbooks(
     let (write, read) = ws_stream.split();
 
     // get the snapshot
-    let last_update_id_arc = Arc::new(Mutex::new(
-        get_snapshot(conf, &mut *orderbook_arc.lock().await).await?,
-    ));
-    let is_first = Arc::new(Mutex::new(true));
-    let prev_u = Arc::new(Mutex::new(0));
+    let mut book = DepthBook::new(conf.depth);
+    book.snapshot(&get_snapshot(conf, ticker, tx).await?)?;
+    let book = Mutex::new(book);
 
     // first we start a task that sends pings to the server every 20 seconds
     let ping_future = utils::ping_sender(write, conf.exchanges.binance.ping_period);
@@ -144,12 +177,10 @@ pub async fn consume_orderbooks(
     // now that we have the order_book snapshot, we can process updates
     let read_future = {
         read.for_each(|message| async {
-            let mut is_first_lk = is_first.lock().await;
-            let mut prev_u_lk = prev_u.lock().await;
-            let mut last_update_id = last_update_id_arc.lock().await;
-            let mut orderbook = orderbook_arc.lock().await;
+            let mut book = book.lock().await;
             match message {
                 Ok(message) => {
+                    tx.record_message(&message);
                     let msg = match utils::handle_message(message) {
                         Ok(s) => s,
                         Err(e) => {
@@ -158,72 +189,27 @@ pub async fn consume_orderbooks(
                         }
                     };
                     match serde_json::from_str::<BinanceOrderbookUpdateMessage>(&msg) {
-                        Ok(orderbook_message) => {
-                            if orderbook_message.last_update_id <= *last_update_id {
-                                return;
-                            }
-                            if !*is_first_lk && *prev_u_lk + 1 != orderbook_message.first_update_id
-                            {
-                                error!("Update out of sequence.");
-                                match get_snapshot(conf, &mut orderbook).await {
-                                    Ok(lu) => *last_update_id = lu,
-                                    Err(e) => {
-                                        error!("Failed to get snapshot. {}", e);
-                                        return;
-                                    }
-                                }
-                                *is_first_lk = true;
-                                return;
+                        Ok(orderbook_message) => match book.update(orderbook_message) {
+                            DepthUpdate::Orderbook(orderbook) => {
+                                if let Err(_item) = tx.send(FeedEvent::Orderbook(orderbook)).await {
+                                    error!("Error sending binance orderbook item.");
+                                };
                             }
-
-                            if *is_first_lk
-                                && orderbook_message.first_update_id <= *last_update_id + 1
-                                && orderbook_message.last_update_id >= *last_update_id + 1
-                            {
-                                *is_first_lk = false;
-                            } else if *is_first_lk {
+                            DepthUpdate::Stale => {}
+                            DepthUpdate::OutOfSequence => {
                                 error!("Update out of sequence.");
-                                match get_snapshot(conf, &mut orderbook).await {
-                                    Ok(lu) => *last_update_id = lu,
+                                let snapshot = match get_snapshot(conf, ticker, tx).await {
+                                    Ok(snapshot) => snapshot,
                                     Err(e) => {
                                         error!("Failed to get snapshot. {}", e);
                                         return;
                                     }
+                                };
+                                if let Err(e) = book.snapshot(&snapshot) {
+                                    error!("Failed to get snapshot. {}", e);
                                 }
-                                *is_first_lk = true;
-                                return;
                             }
-
-                            *prev_u_lk = orderbook_message.last_update_id;
-
-                            let bids_in = orderbook_message.bids;
-                            utils::handle_binance_update_message(
-                                bids_in,
-                                &mut orderbook,
-                                conf.depth,
-                                true,
-                            );
-
-                            let asks_in = orderbook_message.asks;
-                            utils::handle_binance_update_message(
-                                asks_in,
-                                &mut orderbook,
-                                conf.depth,
-                                false,
-                            );
-
-                            // reduce the depth of the orderbook if required
-                            let orderbook_reduced = orderbook.reduce(conf.depth);
-
-                            if let Err(_item) = tx
-                                .send(Result::<Orderbooks, Status>::Ok(Orderbooks::Binance(
-                                    orderbook_reduced,
-                                )))
-                                .await
-                            {
-                                error!("Error sending binance orderbook item.");
-                            };
-                        }
+                        },
                         Err(err) => {
                             // JRF TODO do I need to reconnect when this happens?
                             debug!("Message is not an Orderbook message. {}: msg {}", err, msg);
@@ -242,24 +228,30 @@ pub async fn consume_orderbooks(
     Ok(())
 }
 
-// Get a snapshot of the orderbook from the binance API server. This async function returns a promise
-// that resolves to a Result<lastUpdateId> returned with the orderbook data. The bids and asks are stored
-// in the orderbook reference object that is passed into the function call.
+// Get a snapshot of the orderbook from the binance API server. The snapshot is recorded along
+// with the frames of the connection.
 async fn get_snapshot(
     conf: &config::Server,
-    orderbook: &mut Orderbook,
-) -> Result<u64, Box<dyn Error + Send + Sync>> {
-    let api_base = url::Url::parse(&conf.exchanges.binance.api.as_str())?;
+    ticker: &str,
+    tx: &FeedSender,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let api_base = url::Url::parse(conf.exchanges.binance.api.as_str())?;
     let api_channel = format!(
         "/api/v3/depth?symbol={}&limit={}",
-        &conf.ticker.to_uppercase(),
+        ticker.to_uppercase(),
         100
     );
     let api_url = api_base.join(api_channel.as_str())?;
     let snapshot = reqwest::get(api_url).await?.text().await?;
-    let orderbook_message = serde_json::from_str::<BinanceOrderbookMessage>(&snapshot)?;
-    orderbook.bids.clear();
-    orderbook.asks.clear();
+    tx.record(Kind::Snapshot, &snapshot);
+    Ok(snapshot)
+}
+
+// Parse a message of the partial book depth stream.
+pub fn parse_reduced_orderbook(msg: &str) -> serde_json::Result<Orderbook> {
+    let orderbook_message = serde_json::from_str::<BinanceOrderbookMessage>(msg)?;
+    let mut orderbook = Orderbook::new();
+    orderbook.sequence = orderbook_message.last_update_id;
     for bid in orderbook_message.bids {
         orderbook
             .bids
@@ -270,5 +262,196 @@ async fn get_snapshot(
             .asks
             .insert(ask.price(), ExchangeOrderbookLevel::Binance(ask).into());
     }
-    Ok(orderbook_message.last_update_id)
+    Ok(orderbook)
+}
+
+pub fn parse_trade(msg: &str) -> serde_json::Result<Trade> {
+    Ok(serde_json::from_str::<BinanceTradeMessage>(msg)?.into())
+}
+
+pub enum DepthUpdate {
+    Orderbook(Orderbook),
+    // the update is older than the snapshot.
+    Stale,
+    // an update was missed, the book must be resynced from a new snapshot.
+    OutOfSequence,
+}
+
+// Local orderbook maintained from a snapshot and the diff depth stream, following the rules listed
+// above consume_orderbooks.
+pub struct DepthBook {
+    orderbook: Orderbook,
+    depth: usize,
+    last_update_id: u64,
+    is_first: bool,
+    prev_u: u64,
+}
+
+impl DepthBook {
+    pub fn new(depth: usize) -> Self {
+        DepthBook {
+            orderbook: Orderbook::new(),
+            depth,
+            last_update_id: 0,
+            is_first: true,
+            prev_u: 0,
+        }
+    }
+
+    // Reset the book to a snapshot of the binance API server.
+    pub fn snapshot(&mut self, snapshot: &str) -> serde_json::Result<()> {
+        let orderbook_message = serde_json::from_str::<BinanceOrderbookMessage>(snapshot)?;
+        let orderbook = &mut self.orderbook;
+        orderbook.bids.clear();
+        orderbook.asks.clear();
+        orderbook.sequence = orderbook_message.last_update_id;
+        for bid in orderbook_message.bids {
+            orderbook
+                .bids
+                .insert(bid.price(), ExchangeOrderbookLevel::Binance(bid).into());
+        }
+        for ask in orderbook_message.asks {
+            orderbook
+                .asks
+                .insert(ask.price(), ExchangeOrderbookLevel::Binance(ask).into());
+        }
+        self.last_update_id = orderbook_message.last_update_id;
+        self.is_first = true;
+        Ok(())
+    }
+
+    pub fn update(&mut self, orderbook_message: BinanceOrderbookUpdateMessage) -> DepthUpdate {
+        if orderbook_message.last_update_id <= self.last_update_id {
+            return DepthUpdate::Stale;
+        }
+        if !self.is_first && self.prev_u + 1 != orderbook_message.first_update_id {
+            return DepthUpdate::OutOfSequence;
+        }
+        if self.is_first
+            && orderbook_message.first_update_id <= self.last_update_id + 1
+            && orderbook_message.last_update_id > self.last_update_id
+        {
+            self.is_first = false;
+        } else if self.is_first {
+            return DepthUpdate::OutOfSequence;
+        }
+
+        self.prev_u = orderbook_message.last_update_id;
+        self.orderbook.sequence = orderbook_message.last_update_id;
+        utils::handle_binance_update_message(
+            orderbook_message.bids,
+            &mut self.orderbook,
+            self.depth,
+            true,
+        );
+        utils::handle_binance_update_message(
+            orderbook_message.asks,
+            &mut self.orderbook,
+            self.depth,
+            false,
+        );
+
+        // reduce the depth of the orderbook if required
+        DepthUpdate::Orderbook(self.orderbook.reduce(self.depth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    use crate::{
+        definitions::Orderbooks,
+        feed,
+        mock::{self, binance_depth, binance_update, MockExchange, Step},
+    };
+
+    #[tokio::test]
+    async fn depth_stream_refetches_snapshot_after_gap() {
+        let bid = [("0.0024", "10")];
+        let ask = [("0.0026", "5")];
+        let script = vec![
+            Step::Send("{not json".into()),
+            Step::Send(binance_update(95, 100, &bid, &ask)),
+            Step::Send(binance_update(99, 102, &[("0.0025", "1")], &[])),
+            Step::Ping,
+            Step::Send(binance_update(103, 104, &[], &[("0.0026", "0")])),
+            // updates 105 to 106 are missing.
+            Step::Send(binance_update(107, 108, &[("0.0023", "2")], &[])),
+            Step::Send(binance_update(199, 201, &[("0.0022", "3")], &[])),
+        ];
+        let snapshots = vec![
+            binance_depth(100, &bid, &ask),
+            binance_depth(200, &bid, &[("0.0027", "4")]),
+        ];
+        let exchange = MockExchange::start(vec![script], snapshots).await;
+        let conf = Arc::new(mock::conf(50, Some(&exchange), None));
+
+        let (tx, mut rx) = mpsc::channel(1024);
+        tokio::spawn(feed::supervise(
+            "binance",
+            move |tx| {
+                let conf = conf.clone();
+                async move { super::consume_orderbooks(&conf, "ltcbtc", &tx).await }
+            },
+            Default::default(),
+            1,
+            None,
+            Orderbooks::Binance,
+            tx,
+        ));
+        // the stale update is dropped and the gap resyncs the book from a new snapshot.
+        assert_eq!(mock::sequences(&mut rx, 3).await, vec![102, 104, 201]);
+        let requests = exchange.requests();
+        assert_eq!(requests[0], "/ws/ltcbtc@depth@100ms");
+        let snapshot_requests = requests
+            .iter()
+            .filter(|r| *r == "/api/v3/depth?symbol=LTCBTC&limit=100")
+            .count();
+        assert_eq!(snapshot_requests, 2);
+        assert!(exchange.until(|e| e.pings() >= 1 && e.pongs() == 1).await);
+    }
+
+    #[tokio::test]
+    async fn partial_depth_stream_forwards_every_book() {
+        let ask = [("0.0026", "5")];
+        let scripts = vec![
+            vec![
+                Step::Send("{not json".into()),
+                Step::Send(binance_depth(10, &[("0.0024", "10")], &ask)),
+                Step::Send(binance_depth(11, &[("0.0025", "1")], &ask)),
+                Step::Close,
+            ],
+            vec![
+                Step::Ping,
+                Step::Send(binance_depth(12, &[("0.0023", "2")], &ask)),
+            ],
+        ];
+        let exchange = MockExchange::start(scripts, vec![]).await;
+        let mut conf = mock::conf(10, Some(&exchange), None);
+        conf.exchanges.binance.period = Some("1000ms".into());
+        let conf = Arc::new(conf);
+
+        let (tx, mut rx) = mpsc::channel(1024);
+        tokio::spawn(feed::supervise(
+            "binance",
+            move |tx| {
+                let conf = conf.clone();
+                async move { super::consume_reduced_orderbooks(&conf, "ltcbtc", &tx).await }
+            },
+            Default::default(),
+            1,
+            None,
+            Orderbooks::Binance,
+            tx,
+        ));
+        // every partial book is a full book, the closed connection is relaunched.
+        assert_eq!(mock::sequences(&mut rx, 3).await, vec![10, 11, 12]);
+        assert_eq!(
+            exchange.requests(),
+            vec!["/ws/ltcbtc@depth10@1000ms", "/ws/ltcbtc@depth10@1000ms"]
+        );
+        assert!(exchange.until(|e| e.pongs() == 1).await);
+    }
 }
@@ -1,23 +1,28 @@
 use futures::{SinkExt, StreamExt};
-use log::{debug, error, info};
-use serde_json;
+use log::{debug, error, info, warn};
 use std::error::Error;
-use tokio::sync::mpsc;
 use tokio_tungstenite::connect_async;
-use tonic::Status;
 
 use crate::{
     config,
-    definitions::{BitstampOrderbookMessage, ExchangeOrderbookLevel, Orderbook, Orderbooks},
+    definitions::{
+        BitstampEventMessage, BitstampOrderbookMessage, BitstampTradeMessage,
+        ExchangeOrderbookLevel, FeedEvent, Orderbook,
+    },
+    feed::FeedSender,
+    orderbook::Trade,
     utils,
 };
 
+const REQUEST_RECONNECT: &str = "bts:request_reconnect";
+
 pub async fn consume_orderbooks(
     conf: &config::Server,
-    tx: &mpsc::Sender<Result<Orderbooks, Status>>,
+    ticker: &str,
+    tx: &FeedSender,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Bitstamp Collector Started, attempting to connect to websocket server...");
-    let url = url::Url::parse(&conf.exchanges.bitstamp.websocket.as_str())?;
+    let url = url::Url::parse(conf.exchanges.bitstamp.websocket.as_str())?;
     let (ws_stream, _) = connect_async(url).await?;
     info!("Bitstamp WebSocket handshake has been successfully completed.");
 
@@ -26,7 +31,7 @@ pub async fn consume_orderbooks(
     // send json to ws to select channel
     let buf = format!(
         "{{\"event\":\"bts:subscribe\",\"data\":{{\"channel\":\"order_book_{}\"}}}}",
-        conf.ticker
+        ticker
     );
     write.send(buf.into()).await?;
 
@@ -35,9 +40,9 @@ pub async fn consume_orderbooks(
 
     let read_future = {
         read.for_each(|message| async {
-            let mut orderbook = Orderbook::new();
             match message {
                 Ok(message) => {
+                    tx.record_message(&message);
                     let msg = match utils::handle_message(message) {
                         Ok(s) => s,
                         Err(e) => {
@@ -45,29 +50,18 @@ pub async fn consume_orderbooks(
                             return;
                         }
                     };
-                    match serde_json::from_str::<BitstampOrderbookMessage>(&msg) {
-                        Ok(orderbook_message) => {
-                            for bid in orderbook_message.data.bids {
-                                orderbook.bids.insert(
-                                    bid.price(),
-                                    ExchangeOrderbookLevel::Bitstamp(bid).into(),
-                                );
-                            }
-                            for ask in orderbook_message.data.asks {
-                                orderbook.asks.insert(
-                                    ask.price(),
-                                    ExchangeOrderbookLevel::Bitstamp(ask).into(),
-                                );
-                            }
-                            if let Err(_item) = tx
-                                .send(Result::<Orderbooks, Status>::Ok(Orderbooks::Bitstamp(
-                                    orderbook.reduce(conf.depth),
-                                )))
-                                .await
-                            {
+                    match parse_orderbook(&msg, conf.depth) {
+                        Ok(orderbook) => {
+                            if let Err(_item) = tx.send(FeedEvent::Orderbook(orderbook)).await {
                                 error!("Error sending bitstamp orderbook item.");
                             };
                         }
+                        Err(_) if is_reconnect_request(&msg) => {
+                            warn!("Bitstamp requested a reconnect.");
+                            if let Err(_item) = tx.send(FeedEvent::Reconnect).await {
+                                error!("Error sending bitstamp reconnect request.");
+                            };
+                        }
                         Err(err) => {
                             // JRF TODO do I need to reconnect when this happens?
                             debug!("Message is not an Orderbook message. {}: msg {}", err, msg);
@@ -85,3 +79,156 @@ pub async fn consume_orderbooks(
     error!("Websocket failed and closed!");
     Ok(())
 }
+
+// Consume the live trades channel of the ticker.
+pub async fn consume_trades(
+    conf: &config::Server,
+    ticker: &str,
+    tx: &FeedSender,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Bitstamp Trade Collector Started, attempting to connect to websocket server...");
+    let url = url::Url::parse(conf.exchanges.bitstamp.websocket.as_str())?;
+    let (ws_stream, _) = connect_async(url).await?;
+    info!("Bitstamp trade WebSocket handshake has been successfully completed.");
+
+    let (mut write, read) = ws_stream.split();
+    let buf = format!(
+        "{{\"event\":\"bts:subscribe\",\"data\":{{\"channel\":\"live_trades_{}\"}}}}",
+        ticker
+    );
+    write.send(buf.into()).await?;
+    let ping_future = utils::ping_sender(write, conf.exchanges.bitstamp.ping_period);
+
+    let read_future = read.for_each(|message| async {
+        match message {
+            Ok(message) => {
+                tx.record_message(&message);
+                let msg = match utils::handle_message(message) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        debug!("{}", e);
+                        return;
+                    }
+                };
+                match parse_trade(&msg) {
+                    Ok(trade) => {
+                        if let Err(_item) = tx.send(FeedEvent::Trade(trade)).await {
+                            error!("Error sending bitstamp trade item.");
+                        };
+                    }
+                    Err(_) if is_reconnect_request(&msg) => {
+                        warn!("Bitstamp requested a reconnect.");
+                        if let Err(_item) = tx.send(FeedEvent::Reconnect).await {
+                            error!("Error sending bitstamp reconnect request.");
+                        };
+                    }
+                    Err(err) => {
+                        debug!("Message is not a Trade message. {}: msg {}", err, msg);
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Data was not a message!");
+                error!("{}", err);
+            }
+        }
+    });
+    futures::future::select(Box::pin(read_future), Box::pin(ping_future)).await;
+    error!("Websocket failed and closed!");
+    Ok(())
+}
+
+// Parse a message of the orderbook channel, reduced to the configured depth.
+pub fn parse_orderbook(msg: &str, depth: usize) -> serde_json::Result<Orderbook> {
+    let orderbook_message = serde_json::from_str::<BitstampOrderbookMessage>(msg)?;
+    let mut orderbook = Orderbook::new();
+    orderbook.sequence = orderbook_message.data.microtimestamp;
+    for bid in orderbook_message.data.bids {
+        orderbook
+            .bids
+            .insert(bid.price(), ExchangeOrderbookLevel::Bitstamp(bid).into());
+    }
+    for ask in orderbook_message.data.asks {
+        orderbook
+            .asks
+            .insert(ask.price(), ExchangeOrderbookLevel::Bitstamp(ask).into());
+    }
+    Ok(orderbook.reduce(depth))
+}
+
+pub fn parse_trade(msg: &str) -> serde_json::Result<Trade> {
+    Ok(serde_json::from_str::<BitstampTradeMessage>(msg)?
+        .data
+        .into())
+}
+
+fn is_reconnect_request(msg: &str) -> bool {
+    serde_json::from_str::<BitstampEventMessage>(msg)
+        .is_ok_and(|event| event.event == REQUEST_RECONNECT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Notify};
+
+    use crate::{
+        definitions::Orderbooks,
+        feed,
+        mock::{self, bitstamp_book, bitstamp_reconnect, bitstamp_subscribed, MockExchange, Step},
+    };
+
+    #[tokio::test]
+    async fn reconnect_request_and_disconnect_are_recovered() {
+        let bid = [("0.0024", "10")];
+        let ask = [("0.0026", "5")];
+        let connected = Arc::new(Notify::new());
+        let delivered = Arc::new(Notify::new());
+        let scripts = vec![
+            vec![
+                Step::Send(bitstamp_subscribed("order_book_ltcbtc")),
+                Step::Send(bitstamp_book(1_000, &bid, &ask)),
+                Step::Send(r#"{"data":{"bids":"#.into()),
+                Step::Send(bitstamp_reconnect()),
+                // the old connection keeps delivering until the new one has synced.
+                Step::Wait(connected.clone()),
+                Step::Send(bitstamp_book(2_000, &bid, &ask)),
+                // closed before the new connection has synced, which takes over.
+                Step::Close,
+            ],
+            vec![
+                Step::Wait(delivered.clone()),
+                Step::Send(bitstamp_book(3_000, &bid, &ask)),
+                Step::Drop,
+            ],
+            vec![Step::Send(bitstamp_book(4_000, &bid, &ask))],
+        ];
+        let exchange = MockExchange::start(scripts, vec![]).await;
+        let conf = Arc::new(mock::conf(10, None, Some(&exchange)));
+
+        let (tx, mut rx) = mpsc::channel(1024);
+        tokio::spawn(feed::supervise(
+            "bitstamp",
+            move |tx| {
+                let conf = conf.clone();
+                async move { super::consume_orderbooks(&conf, "ltcbtc", &tx).await }
+            },
+            Default::default(),
+            1,
+            None,
+            Orderbooks::Bitstamp,
+            tx,
+        ));
+        let subscribe = r#"{"event":"bts:subscribe","data":{"channel":"order_book_ltcbtc"}}"#;
+        let subscribed = |e: &MockExchange| e.requests().iter().filter(|r| *r == subscribe).count();
+        assert_eq!(mock::sequences(&mut rx, 1).await, vec![1_000]);
+        assert!(exchange.until(|e| subscribed(e) == 2).await);
+        connected.notify_one();
+        assert_eq!(mock::sequences(&mut rx, 1).await, vec![2_000]);
+        delivered.notify_one();
+        // the dropped connection is relaunched.
+        assert_eq!(mock::sequences(&mut rx, 2).await, vec![3_000, 4_000]);
+        assert_eq!(exchange.connections(), 3);
+        assert!(exchange.until(|e| subscribed(e) == 3).await);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::broadcast;
+
+use crate::{error::ObaggError, orderbook::Candle, orderbook::CandleRequest};
+
+// Bar lengths, in seconds.
+pub const INTERVALS: [u32; 3] = [1, 60, 300];
+pub const CONSOLIDATED: &str = "consolidated";
+// Time, in microseconds, a bar stays open after its end for trades that arrive late.
+const GRACE: u64 = 1_000_000;
+// Number of completed bars kept per exchange and interval for late subscribers.
+const HISTORY: usize = 1000;
+
+// Builds the bars of every exchange and interval, along with the consolidated bars of all
+// exchanges, from price observations.
+pub struct CandleBuilder {
+    symbol: String,
+    open: HashMap<(String, u32), Candle>,
+    // start of the last completed bar of each source and interval.
+    completed: HashMap<(String, u32), u64>,
+}
+
+impl CandleBuilder {
+    pub fn new(symbol: &str) -> Self {
+        CandleBuilder {
+            symbol: symbol.into(),
+            open: HashMap::new(),
+            completed: HashMap::new(),
+        }
+    }
+
+    // Add a trade on an exchange at its timestamp, in microseconds, to the bars of the exchange
+    // and to the consolidated bars. Returns the bars it completed.
+    pub fn trade(
+        &mut self,
+        exchange: &str,
+        price: f64,
+        amount: f64,
+        timestamp: u64,
+    ) -> Vec<Candle> {
+        let mut closed = vec![];
+        for source in [exchange, CONSOLIDATED] {
+            self.add(source, price, Some(amount), timestamp, &mut closed);
+        }
+        closed
+    }
+
+    // Add a price sampled from the book of a source when trades are not consumed, it carries no
+    // volume. Returns the bars it completed.
+    pub fn sample(&mut self, source: &str, price: f64, timestamp: u64) -> Vec<Candle> {
+        let mut closed = vec![];
+        self.add(source, price, None, timestamp, &mut closed);
+        closed
+    }
+
+    fn add(
+        &mut self,
+        source: &str,
+        price: f64,
+        amount: Option<f64>,
+        timestamp: u64,
+        closed: &mut Vec<Candle>,
+    ) {
+        for interval in INTERVALS {
+            let length = interval as u64 * 1_000_000;
+            let open_time = timestamp - timestamp % length;
+            let key = (source.to_string(), interval);
+            // late observation of a bar that has already been completed.
+            if self.completed.get(&key).is_some_and(|t| open_time <= *t) {
+                continue;
+            }
+            match self.open.get(&key) {
+                Some(candle) if open_time < candle.open_time => continue,
+                Some(candle) if open_time > candle.open_time => {
+                    closed.extend(self.close(&key));
+                }
+                _ => {}
+            }
+            let candle = self.open.entry(key).or_insert_with(|| Candle {
+                symbol: self.symbol.clone(),
+                exchange: source.into(),
+                interval,
+                open_time,
+                open: price,
+                high: price,
+                low: price,
+                ..Default::default()
+            });
+            candle.high = candle.high.max(price);
+            candle.low = candle.low.min(price);
+            candle.close = price;
+            if let Some(amount) = amount {
+                candle.volume += amount;
+                candle.trades += 1;
+            }
+        }
+    }
+
+    // Complete the bars that ended before now, in microseconds.
+    pub fn roll(&mut self, now: u64) -> Vec<Candle> {
+        let due: Vec<(String, u32)> = self
+            .open
+            .iter()
+            .filter(|(_, c)| c.open_time + c.interval as u64 * 1_000_000 + GRACE <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        due.iter().filter_map(|key| self.close(key)).collect()
+    }
+
+    fn close(&mut self, key: &(String, u32)) -> Option<Candle> {
+        let candle = self.open.remove(key)?;
+        self.completed.insert(key.clone(), candle.open_time);
+        Some(candle)
+    }
+}
+
+// Completed bars, kept in a ring per exchange and interval and broadcast to the subscribers.
+pub struct CandleHistory {
+    ring: HashMap<(String, u32), VecDeque<Candle>>,
+    tx: broadcast::Sender<Candle>,
+}
+
+impl CandleHistory {
+    pub fn new() -> Self {
+        CandleHistory {
+            ring: HashMap::new(),
+            tx: broadcast::channel(1024).0,
+        }
+    }
+
+    pub fn publish(&mut self, candle: Candle) {
+        let ring = self
+            .ring
+            .entry((candle.exchange.clone(), candle.interval))
+            .or_default();
+        if ring.len() == HISTORY {
+            ring.pop_front();
+        }
+        ring.push_back(candle.clone());
+        // fails only when no client is listening.
+        let _ = self.tx.send(candle);
+    }
+
+    // The bars kept for the request, oldest first, and the receiver of the bars completed next.
+    pub fn subscribe(&self, req: &CandleRequest) -> (Vec<Candle>, broadcast::Receiver<Candle>) {
+        let mut backfill: Vec<Candle> = self
+            .ring
+            .values()
+            .flatten()
+            .filter(|c| matches(req, c))
+            .cloned()
+            .collect();
+        backfill.sort_by_key(|c| (c.open_time, c.interval));
+        (backfill, self.tx.subscribe())
+    }
+}
+
+impl Default for CandleHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn validate(req: &CandleRequest) -> Result<(), ObaggError> {
+    if req.interval != 0 && !INTERVALS.contains(&req.interval) {
+        return Err(ObaggError(format!(
+            "Invalid interval : must be one of {INTERVALS:?} seconds"
+        )));
+    }
+    Ok(())
+}
+
+pub fn matches(req: &CandleRequest, candle: &Candle) -> bool {
+    (req.interval == 0 || req.interval == candle.interval)
+        && (req.exchange.is_empty() || req.exchange == candle.exchange)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CandleBuilder, CandleHistory, CONSOLIDATED};
+    use crate::orderbook::CandleRequest;
+
+    const SECOND: u64 = 1_000_000;
+
+    #[test]
+    fn trades_build_bars_per_exchange_and_consolidated() {
+        let mut builder = CandleBuilder::new("ltcbtc");
+        let start = 1_661_585_400 * SECOND;
+        assert!(builder.trade("binance", 10.0, 1.0, start).is_empty());
+        assert!(builder
+            .trade("bitstamp", 12.0, 2.0, start + 200_000)
+            .is_empty());
+        assert!(builder
+            .trade("binance", 9.0, 1.0, start + 500_000)
+            .is_empty());
+
+        // the next second completes the 1s bars of binance and of all exchanges.
+        let mut closed = builder.trade("binance", 11.0, 1.0, start + SECOND);
+        closed.sort_by(|a, b| a.exchange.cmp(&b.exchange));
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].exchange, "binance");
+        assert_eq!(
+            (
+                closed[0].open,
+                closed[0].high,
+                closed[0].low,
+                closed[0].close
+            ),
+            (10.0, 10.0, 9.0, 9.0)
+        );
+        assert_eq!(closed[1].exchange, CONSOLIDATED);
+        assert_eq!(closed[1].high, 12.0);
+        assert_eq!(closed[1].volume, 4.0);
+        assert_eq!(closed[1].trades, 3);
+
+        // bars with no trade since are completed once their grace period has passed.
+        let closed = builder.roll(start + 3 * SECOND);
+        assert_eq!(closed.len(), 3);
+        assert!(closed.iter().all(|c| c.interval == 1));
+        // a trade arriving after its bar was completed is dropped from it.
+        builder.trade("bitstamp", 20.0, 1.0, start + 500_000);
+        assert!(builder.roll(start + 3 * SECOND).is_empty());
+
+        let mut history = CandleHistory::new();
+        for candle in closed {
+            history.publish(candle);
+        }
+        let req = CandleRequest {
+            interval: 1,
+            exchange: "bitstamp".into(),
+        };
+        let (backfill, _rx) = history.subscribe(&req);
+        assert_eq!(backfill.len(), 1);
+        assert_eq!(backfill[0].close, 12.0);
+
+        // sampled mid prices only move the bar of their source and carry no volume.
+        builder.sample(CONSOLIDATED, 10.5, start + 3 * SECOND);
+        let closed = builder.sample(CONSOLIDATED, 10.7, start + 4 * SECOND);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(
+            (closed[0].close, closed[0].volume, closed[0].trades),
+            (10.5, 0.0, 0)
+        );
+    }
+}
@@ -0,0 +1,437 @@
+// Validation of a configuration file before it is deployed. Every problem found is reported, with
+// the line of the offending key when it is present in the file, or else the override variable
+// setting it, rather than surfacing later as an error inside a spawned consumer.
+use serde_yaml::Value;
+use std::{collections::HashMap, error::Error, fmt, path::Path};
+
+use crate::{
+    config,
+    definitions::{BINANCE, BITSTAMP},
+    error::ObaggError,
+};
+
+// Update speeds of the binance depth streams.
+const BINANCE_PERIODS: [&str; 2] = ["100ms", "1000ms"];
+// Depths of the binance partial book depth stream, used for depths of 20 and under.
+const BINANCE_PARTIAL_DEPTHS: [usize; 3] = [5, 10, 20];
+// Levels of the binance REST snapshot maintained from the diff depth stream.
+const BINANCE_SNAPSHOT_DEPTH: usize = 100;
+// Levels of the bitstamp order_book channel.
+const BITSTAMP_DEPTH: usize = 100;
+
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub line: Option<usize>,
+    // dotted path of the offending key, e.g. exchanges.binance.period, empty for the whole file.
+    pub key: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(key: &str, message: String) -> Self {
+        Problem {
+            line: None,
+            key: key.into(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+// Check the configuration file at path with the overrides of the given variables. Returns the
+// problems found, none when the configuration is valid.
+pub fn check_config(
+    path: &Path,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<Problem>, Box<dyn Error + Send + Sync>> {
+    let yaml = std::fs::read_to_string(path).map_err(|e| {
+        ObaggError(format!(
+            "Error reading configuration file {} : {e}",
+            path.display()
+        ))
+    })?;
+    check_yaml(&yaml, vars)
+}
+
+pub fn check_yaml(
+    yaml: &str,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<Problem>, Box<dyn Error + Send + Sync>> {
+    let value: Value = match serde_yaml::from_str(yaml) {
+        Ok(value) => value,
+        Err(e) => {
+            return Ok(vec![Problem {
+                line: e.location().map(|l| l.line()),
+                key: String::new(),
+                message: e.to_string(),
+            }])
+        }
+    };
+    let vars: Vec<(String, String)> = vars.into_iter().collect();
+    // keys set by an override, with the variable setting them.
+    let overrides: Vec<(String, String)> = vars
+        .iter()
+        .filter_map(|(var, _)| Some((config::override_key(var)?, var.clone())))
+        .collect();
+    let value = config::layered(value, vars)?;
+
+    let mut problems = vec![];
+    let mut unknown = |path: serde_ignored::Path| {
+        problems.push(Problem::new(&ignored_key(&path), "unknown key".into()))
+    };
+    let parsed: Result<config::Server, _> =
+        serde_path_to_error::deserialize(serde_ignored::Deserializer::new(value, &mut unknown));
+    match parsed {
+        Ok(conf) => problems.extend(validate(&conf)),
+        Err(e) => {
+            let key = config::dotted_path(e.path());
+            problems.push(Problem::new(&key, e.into_inner().to_string()));
+        }
+    }
+
+    let lines = key_lines(yaml);
+    for problem in &mut problems {
+        let overridden = overrides
+            .iter()
+            .find(|(key, _)| problem.key == *key || problem.key.starts_with(&format!("{key}.")));
+        match overridden {
+            // the line of the file holds a value the override replaced.
+            Some((_, var)) => problem.message = format!("{} (set by {var})", problem.message),
+            None => problem.line = problem.line.or_else(|| line(&lines, &problem.key)),
+        }
+    }
+    problems.sort_by_key(|p| p.line.unwrap_or(usize::MAX));
+    Ok(problems)
+}
+
+// Check the settings that parse but that the exchanges or the aggregator cannot serve.
+pub fn validate(conf: &config::Server) -> Vec<Problem> {
+    let mut problems = vec![];
+    if conf.depth == 0 {
+        problems.push(Problem::new("depth", "must be at least 1".into()));
+    }
+    if let Some(0) = conf.client_buffer {
+        problems.push(Problem::new("client_buffer", "must be at least 1".into()));
+    }
+
+    for name in [BINANCE, BITSTAMP] {
+        let Some(exchange) = conf.exchanges.get(name).filter(|e| e.enable) else {
+            continue;
+        };
+        let key = |field: &str| format!("exchanges.{name}.{field}");
+        check_url(
+            &mut problems,
+            &key("websocket"),
+            &exchange.websocket,
+            &["ws", "wss"],
+        );
+        // the bitstamp consumers do not call its API.
+        if name == BINANCE || !exchange.api.is_empty() {
+            check_url(
+                &mut problems,
+                &key("api"),
+                &exchange.api,
+                &["http", "https"],
+            );
+        }
+        if exchange.ping_period == 0 {
+            problems.push(Problem::new(
+                &key("ping_period"),
+                "must be at least 1".into(),
+            ));
+        }
+        if let Some(0) = exchange.connections {
+            problems.push(Problem::new(
+                &key("connections"),
+                "must be at least 1".into(),
+            ));
+        }
+        if let Some(period) = &exchange.period {
+            if name != BINANCE {
+                problems.push(Problem::new(
+                    &key("period"),
+                    format!("not supported by {name}"),
+                ));
+            } else if !BINANCE_PERIODS.contains(&period.as_str()) {
+                problems.push(Problem::new(
+                    &key("period"),
+                    format!("{period} is not one of {}", BINANCE_PERIODS.join(", ")),
+                ));
+            }
+        }
+        if let Some(instruments) = &conf.instruments {
+            if !instruments
+                .iter()
+                .any(|i| i.exchange == name && i.ticker == conf.ticker)
+            {
+                problems.push(Problem::new(
+                    "instruments",
+                    format!("no {name} instrument for the ticker {}", conf.ticker),
+                ));
+            }
+        }
+    }
+
+    let depth = conf.depth;
+    if conf.exchanges.binance.enable {
+        if (1..=20).contains(&depth) && !BINANCE_PARTIAL_DEPTHS.contains(&depth) {
+            problems.push(Problem::new(
+                "depth",
+                format!(
+                    "binance streams depths of 20 and under as partial books of 5, 10 or 20 \
+                     levels, not {depth}"
+                ),
+            ));
+        } else if depth > BINANCE_SNAPSHOT_DEPTH {
+            problems.push(Problem::new(
+                "depth",
+                format!(
+                    "binance books are limited to {BINANCE_SNAPSHOT_DEPTH} levels, not {depth}"
+                ),
+            ));
+        }
+    }
+    if conf.exchanges.bitstamp.enable && depth > BITSTAMP_DEPTH {
+        problems.push(Problem::new(
+            "depth",
+            format!("bitstamp books are limited to {BITSTAMP_DEPTH} levels, not {depth}"),
+        ));
+    }
+
+    for (i, quote) in conf.quotes.iter().flatten().enumerate() {
+        check_exchanges(
+            &mut problems,
+            &format!("quotes.{i}.exchanges"),
+            &quote.exchanges,
+        );
+        check_exchanges(
+            &mut problems,
+            &format!("quotes.{i}.rate.exchanges"),
+            &quote.rate.exchanges,
+        );
+    }
+    for (i, implied) in conf.implied.iter().flatten().enumerate() {
+        check_exchanges(
+            &mut problems,
+            &format!("implied.{i}.exchange"),
+            std::slice::from_ref(&implied.exchange),
+        );
+    }
+    problems
+}
+
+fn check_url(problems: &mut Vec<Problem>, key: &str, value: &str, schemes: &[&str]) {
+    match url::Url::parse(value) {
+        Ok(url) if schemes.contains(&url.scheme()) => {}
+        Ok(url) => problems.push(Problem::new(
+            key,
+            format!(
+                "scheme {} of {value} is not one of {}",
+                url.scheme(),
+                schemes.join(", ")
+            ),
+        )),
+        Err(e) => problems.push(Problem::new(key, format!("invalid URL {value:?} : {e}"))),
+    }
+}
+
+fn check_exchanges(problems: &mut Vec<Problem>, key: &str, exchanges: &[String]) {
+    for exchange in exchanges {
+        if exchange != BINANCE && exchange != BITSTAMP {
+            problems.push(Problem::new(key, format!("unknown exchange {exchange}")));
+        }
+    }
+}
+
+// Dotted path of a key ignored by the deserializer, e.g. exchanges.binance.perod.
+fn ignored_key(path: &serde_ignored::Path) -> String {
+    let parent = |parent: &serde_ignored::Path| {
+        let parent = ignored_key(parent);
+        if parent.is_empty() {
+            parent
+        } else {
+            parent + "."
+        }
+    };
+    match path {
+        serde_ignored::Path::Root => String::new(),
+        serde_ignored::Path::Seq { parent: p, index } => parent(p) + &index.to_string(),
+        serde_ignored::Path::Map { parent: p, key } => parent(p) + key,
+        serde_ignored::Path::Some { parent: p }
+        | serde_ignored::Path::NewtypeStruct { parent: p }
+        | serde_ignored::Path::NewtypeVariant { parent: p } => ignored_key(p),
+    }
+}
+
+// Line of a key, or else of the closest key it is nested in, e.g. the line of exchanges.binance
+// for a field missing from it.
+fn line(lines: &HashMap<String, usize>, key: &str) -> Option<usize> {
+    let mut key = key;
+    loop {
+        if let Some(line) = lines.get(key) {
+            return Some(*line);
+        }
+        key = &key[..key.rfind('.')?];
+    }
+}
+
+// Lines of the keys of the block mappings and sequences of a YAML file, by dotted path. The keys
+// of flow mappings, e.g. { enable: true }, are attributed the line of the key holding them.
+fn key_lines(yaml: &str) -> HashMap<String, usize> {
+    struct Parent {
+        indent: usize,
+        path: String,
+        // a sequence item, whose keys are nested deeper than its dash.
+        item: bool,
+    }
+    let mut lines = HashMap::new();
+    let mut parents: Vec<Parent> = vec![];
+    let mut items: HashMap<String, usize> = HashMap::new();
+    for (n, line) in yaml.lines().enumerate() {
+        let mut content = line.trim_start();
+        let mut indent = line.len() - content.len();
+        if content.is_empty() || content.starts_with('#') || content.starts_with("---") {
+            continue;
+        }
+        let path = |parents: &Vec<Parent>, key: &str| match parents.last() {
+            Some(parent) => format!("{}.{key}", parent.path),
+            None => key.to_string(),
+        };
+        while let Some(rest) = content
+            .strip_prefix("- ")
+            .or_else(|| (content == "-").then_some(""))
+        {
+            while parents
+                .last()
+                .is_some_and(|p| p.indent > indent || (p.indent == indent && p.item))
+            {
+                parents.pop();
+            }
+            let sequence = parents.last().map(|p| p.path.clone()).unwrap_or_default();
+            let index = items.entry(sequence).or_default();
+            let item = path(&parents, &index.to_string());
+            *index += 1;
+            lines.insert(item.clone(), n + 1);
+            parents.push(Parent {
+                indent,
+                path: item,
+                item: true,
+            });
+            indent += content.len() - rest.trim_start().len();
+            content = rest.trim_start();
+        }
+        let Some((key, _)) = content
+            .split_once(": ")
+            .or_else(|| content.strip_suffix(':').map(|key| (key, "")))
+        else {
+            continue;
+        };
+        if key.starts_with(['{', '[', '"', '\'']) && !key.ends_with(['"', '\'']) {
+            continue;
+        }
+        while parents.last().is_some_and(|p| p.indent >= indent) {
+            parents.pop();
+        }
+        let key = path(&parents, key.trim().trim_matches(['"', '\'']));
+        lines.insert(key.clone(), n + 1);
+        parents.push(Parent {
+            indent,
+            path: key,
+            item: false,
+        });
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_config, check_yaml};
+    use std::path::Path;
+
+    #[test]
+    fn every_problem_is_reported_with_its_line() {
+        let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("../conf/obagg.yaml");
+        assert_eq!(check_config(&example, []).unwrap(), vec![]);
+
+        let yaml = "
+bind_address: \"127.0.0.1:50051\"
+ticker: ltcbtc
+depth: 15
+identical_level_order: true
+instruments:
+  - exchange: binance
+    ticker: ltcbtc
+  - exchange: bitstamp
+    ticker: btcusd
+    min_notinal: 0.0002
+exchanges:
+  binance:
+    enable: true
+    websocket: \"wss://stream.binance.com:9443\"
+    api: \"api.binance.com\"
+    ping_period: 10
+    period: 250ms
+  bitstamp:
+    enable: true
+    websocket: \"wss//ws.bitstamp.net\"
+    api: \"\"
+    ping_period: 5
+    reconect_period: 60
+";
+        let problems: Vec<String> = check_yaml(yaml, [])
+            .unwrap()
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "line 4: depth: binance streams depths of 20 and under as partial books of 5, 10 \
+                 or 20 levels, not 15",
+                "line 6: instruments: no bitstamp instrument for the ticker ltcbtc",
+                "line 11: instruments.1.min_notinal: unknown key",
+                "line 16: exchanges.binance.api: invalid URL \"api.binance.com\" : relative URL \
+                 without a base",
+                "line 18: exchanges.binance.period: 250ms is not one of 100ms, 1000ms",
+                "line 21: exchanges.bitstamp.websocket: invalid URL \"wss//ws.bitstamp.net\" : \
+                 relative URL without a base",
+                "line 24: exchanges.bitstamp.reconect_period: unknown key",
+            ]
+        );
+
+        let problems = check_yaml(yaml, [("OBAGG__DEPTH".into(), "ten".into())]).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            (problems[0].line, problems[0].key.as_str()),
+            (None, "depth")
+        );
+        assert!(problems[0].message.contains("invalid type"));
+        assert!(problems[0].message.ends_with("(set by OBAGG__DEPTH)"));
+        let vars = [(
+            "OBAGG__EXCHANGES__BINANCE__API".into(),
+            "api.binance.com".into(),
+        )];
+        let problems = check_yaml(yaml, vars).unwrap();
+        let api = problems
+            .iter()
+            .find(|p| p.key == "exchanges.binance.api")
+            .unwrap();
+        assert_eq!(api.line, None);
+        assert!(api
+            .to_string()
+            .ends_with("(set by OBAGG__EXCHANGES__BINANCE__API)"));
+        let problems = check_yaml("ticker: ltcbtc\ndepth: 10\n  bids: 5\n", []).unwrap();
+        assert_eq!((problems.len(), problems[0].line), (1, Some(3)));
+    }
+}
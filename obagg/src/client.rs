@@ -1,14 +1,12 @@
 use http::Uri;
 use log::info;
-use orderbook::{orderbook_aggregator_client::OrderbookAggregatorClient, Empty};
 use std::error::Error;
 use tokio::time::{sleep, Duration};
 
-use crate::config;
-
-pub mod orderbook {
-    tonic::include_proto!("orderbook");
-}
+use crate::{
+    config,
+    orderbook::{orderbook_aggregator_client::OrderbookAggregatorClient, SummaryRequest},
+};
 
 pub async fn client(conf: config::Server) -> Result<(), Box<dyn Error>> {
     let uri = Uri::builder()
@@ -32,7 +30,7 @@ pub async fn client(conf: config::Server) -> Result<(), Box<dyn Error>> {
     info!("Client connected to : {:?}", uri);
 
     let mut client = OrderbookAggregatorClient::new(channel);
-    let request = tonic::Request::new(Empty {});
+    let request = tonic::Request::new(SummaryRequest::default());
     let mut response = client.book_summary_stream(request).await?.into_inner();
 
     // listen to stream
@@ -1,10 +1,18 @@
-use serde::{de::DeserializeOwned, Deserialize};
-use std::{error::Error, net::SocketAddr};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::{
+    error::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
-#[derive(Deserialize)]
-pub struct Apis {
-    pub binance: String,
-}
+use crate::error::ObaggError;
+
+// Variable pointing to the configuration file when none is given on the command line.
+const CONFIG_VAR: &str = "AGGREGATED_ORDERBOOK_CONFIG";
+// Prefix of the variables overriding a key of the configuration file, the nested keys are
+// separated by a double underscore, e.g. OBAGG__EXCHANGES__BINANCE__ENABLE=false.
+const OVERRIDE_PREFIX: &str = "OBAGG__";
 
 #[derive(Deserialize, Clone)]
 pub struct Exchange {
@@ -13,6 +21,12 @@ pub struct Exchange {
     pub websocket: String,
     pub ping_period: u16,
     pub period: Option<String>,
+    // optional period, in seconds, after which the connection is replaced make-before-break.
+    pub reconnect_period: Option<u64>,
+    // optional number of redundant websocket connections kept open to the feed, defaults to 1.
+    pub connections: Option<usize>,
+    // optional taker fee rate, e.g. 0.001 for 0.1%, defaults to 0.
+    pub taker_fee: Option<f64>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -21,30 +35,452 @@ pub struct Exchanges {
     pub bitstamp: Exchange,
 }
 
+impl Exchanges {
+    pub fn get(&self, name: &str) -> Option<&Exchange> {
+        match name {
+            "binance" => Some(&self.binance),
+            "bitstamp" => Some(&self.bitstamp),
+            _ => None,
+        }
+    }
+}
+
+// A market quoted in another currency than the ticker, e.g. btcusdt for a btcusd ticker. Its
+// levels are converted into the ticker quote and merged into the aggregated book.
+#[derive(Deserialize, Clone)]
+pub struct QuoteMarket {
+    pub ticker: String,
+    // name of the quote currency, e.g. usdt, carried by the converted levels.
+    pub quote: String,
+    // exchanges the market is consumed from.
+    pub exchanges: Vec<String>,
+    pub rate: RateMarket,
+}
+
+// The market whose aggregated mid price converts a quote currency into the ticker quote, e.g.
+// usdtusd for usdt into usd.
+#[derive(Deserialize, Clone)]
+pub struct RateMarket {
+    pub ticker: String,
+    pub exchanges: Vec<String>,
+    // optional, set when the market quotes the ticker quote in the other currency, e.g. usdeur.
+    pub invert: Option<bool>,
+}
+
+// A book of the ticker implied from two legs quoted in a common currency on the same exchange,
+// e.g. ltcbtc from ltcusdt and btcusdt.
+#[derive(Deserialize, Clone)]
+pub struct ImpliedMarket {
+    pub exchange: String,
+    // leg pricing the ticker base, e.g. ltcusdt.
+    pub base: String,
+    // leg pricing the ticker quote, e.g. btcusdt.
+    pub quote: String,
+}
+
+// Trading rules of a market on an exchange, as listed in the instrument registry.
+#[derive(Deserialize, Clone)]
+pub struct Instrument {
+    pub exchange: String,
+    pub ticker: String,
+    // optional minimum quantity and notional of an order.
+    pub min_quantity: Option<f64>,
+    pub min_notional: Option<f64>,
+}
+
+// Capture of the raw inbound frames of every exchange connection.
+#[derive(Deserialize, Clone)]
+pub struct Recording {
+    // directory the capture files are written to.
+    pub path: String,
+    // optional size, in bytes of uncompressed frames, after which a new capture file is started,
+    // defaults to 64MiB.
+    pub max_file_size: Option<u64>,
+}
+
+// History of the aggregated books written for research.
+#[derive(Deserialize, Clone)]
+pub struct History {
+    // directory the CSV files are written to, partitioned by date and hour.
+    pub path: String,
+    // optional minimum time, in milliseconds, between two written books. Every change of the
+    // aggregated book is written when unset.
+    pub interval: Option<u64>,
+}
+
+// Snapshots of the aggregated book and status transitions of the feeds, stored in a SQLite
+// database.
+#[derive(Deserialize, Clone)]
+pub struct Snapshots {
+    // path of the database file, created when missing.
+    pub path: String,
+    // optional time, in milliseconds, between two snapshots, defaults to 1000.
+    pub interval: Option<u64>,
+}
+
+// What to do with a client that does not keep up with the aggregated book.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowClientPolicy {
+    // deliver only the latest summary once the client has drained its queue.
+    Conflate,
+    // disconnect the client once it lags more than max_client_lag summaries.
+    Disconnect,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Server {
     pub bind_address: SocketAddr,
     pub depth: usize,
     pub exchanges: Exchanges,
     pub identical_level_order: bool,
+    // optional, order and emit levels by price including the taker fee of their exchange.
+    pub fee_adjusted: Option<bool>,
     pub ticker: String,
-}
-
-fn file_from_env(var: &str) -> Result<std::fs::File, Box<dyn Error + Sync + Send>> {
-    let path = std::env::var(var)?;
-    Ok(std::fs::File::open(path)?)
+    // optional markets in other quote currencies, converted into the ticker quote.
+    pub quotes: Option<Vec<QuoteMarket>>,
+    // optional books implied from two legs, merged under the `<exchange>:implied` label.
+    pub implied: Option<Vec<ImpliedMarket>>,
+    // optional, also consume the trades of the ticker on each enabled exchange.
+    pub trades: Option<bool>,
+    // optional instrument registry listing the trading rules of each market.
+    pub instruments: Option<Vec<Instrument>>,
+    // optional, record every inbound exchange frame to rotating compressed capture files.
+    pub record: Option<Recording>,
+    // optional, write the aggregated books to CSV files.
+    pub history: Option<History>,
+    // optional, store snapshots of the aggregated book in a SQLite database.
+    pub snapshots: Option<Snapshots>,
+    // optional size of the per client summary queue, defaults to 1024.
+    pub client_buffer: Option<usize>,
+    // optional policy applied to clients whose queue is full, defaults to conflate.
+    pub slow_client_policy: Option<SlowClientPolicy>,
+    // optional number of conflated summaries after which a slow client is disconnected when
+    // using the disconnect policy, defaults to 100.
+    pub max_client_lag: Option<u64>,
 }
 
 impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn instrument(&self, exchange: &str, ticker: &str) -> Option<&Instrument> {
+        self.instruments
+            .iter()
+            .flatten()
+            .find(|i| i.exchange == exchange && i.ticker == ticker)
+    }
+
+    // Read the configuration file at path, or else the one pointed to by
+    // AGGREGATED_ORDERBOOK_CONFIG, with the overrides of the process environment.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Self::from_path(&config_path(path)?, std::env::vars())
+    }
+
     pub fn from_env() -> Result<Self, Box<dyn Error + Sync + Send>> {
-        Ok(serde_yaml::from_reader(file_from_env(
-            "AGGREGATED_ORDERBOOK_CONFIG",
+        Self::load(None)
+    }
+
+    // Read the configuration file at path, with the overrides of the given variables.
+    pub fn from_path(
+        path: &Path,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let yaml = std::fs::read_to_string(path).map_err(|e| {
+            ObaggError(format!(
+                "Error reading configuration file {} : {e}",
+                path.display()
+            ))
+        })?;
+        Self::from_yaml(&yaml, vars).map_err(|e| {
+            ObaggError(format!(
+                "Error parsing configuration file {} : {e}",
+                path.display()
+            ))
+            .into()
+        })
+    }
+
+    // Parse a configuration, with the overrides of the given variables.
+    pub fn from_yaml(
+        yaml: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(serde_yaml::from_value(layered(
+            serde_yaml::from_str(yaml)?,
+            vars,
         )?)?)
     }
 }
 
-pub fn read_config<T: DeserializeOwned>() -> T {
-    let file = file_from_env("AGGREGATED_ORDERBOOK_CONFIG")
+// Read the configuration file pointed to by AGGREGATED_ORDERBOOK_CONFIG, panicking on error as
+// before Server::load was added.
+#[deprecated(note = "use config::Server::load or config::Server::from_path")]
+pub fn read_config() -> Server {
+    let path = config_path(None)
         .expect("Error when opening file pointed to by AGGREGATED_ORDERBOOK_CONFIG env variable");
-    serde_yaml::from_reader(file).expect("Error parsing configuration file!")
+    Server::from_path(&path, std::env::vars()).expect("Error parsing configuration file!")
+}
+
+// The configuration file given, or else the one pointed to by AGGREGATED_ORDERBOOK_CONFIG.
+pub fn config_path(path: Option<&Path>) -> Result<PathBuf, ObaggError> {
+    match path {
+        Some(path) => Ok(path.to_path_buf()),
+        None => std::env::var_os(CONFIG_VAR)
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                ObaggError(format!(
+                    "No configuration file : pass --config or set {CONFIG_VAR}"
+                ))
+            }),
+    }
+}
+
+// Apply the overrides of the OBAGG__ variables to a parsed configuration file.
+pub fn layered(
+    mut value: Value,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Value, ObaggError> {
+    let mut overrides = vec![];
+    for (var, raw) in vars {
+        let Some(key) = override_key(&var) else {
+            continue;
+        };
+        let keys: Vec<String> = key.split('.').map(String::from).collect();
+        // the value is read as YAML so that numbers and booleans keep their type, an empty value
+        // is an empty string rather than null.
+        let parsed = match raw.as_str() {
+            "" => Value::String(raw.clone()),
+            _ => serde_yaml::from_str(&raw).unwrap_or_else(|_| Value::String(raw.clone())),
+        };
+        set(&mut value, &keys, parsed)
+            .map_err(|e| ObaggError(format!("Invalid override {var} : {}", e.0)))?;
+        overrides.push((keys, raw));
+    }
+    // a value read as a number, boolean or null is set back as the string it was when the key it
+    // overrides is a string, e.g. OBAGG__TICKER=1e5.
+    while let Err(e) = serde_path_to_error::deserialize::<_, Server>(value.clone()) {
+        let key = dotted_path(e.path());
+        let Some((keys, raw)) = overrides.iter().find(|(keys, _)| keys.join(".") == key) else {
+            break;
+        };
+        let raw = Value::String(raw.clone());
+        if get(&value, keys) == Some(&raw) {
+            break;
+        }
+        set(&mut value, keys, raw)?;
+    }
+    Ok(value)
+}
+
+// Dotted key of the configuration overridden by a variable, e.g. exchanges.binance.enable for
+// OBAGG__EXCHANGES__BINANCE__ENABLE, none for the other variables.
+pub fn override_key(var: &str) -> Option<String> {
+    let key = var.strip_prefix(OVERRIDE_PREFIX)?;
+    Some(
+        key.split("__")
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join("."),
+    )
+}
+
+// Dotted key of the value a deserialization error is found at, e.g. exchanges.binance.period.
+pub fn dotted_path(path: &serde_path_to_error::Path) -> String {
+    path.iter()
+        .filter_map(|segment| match segment {
+            serde_path_to_error::Segment::Map { key } => Some(key.clone()),
+            serde_path_to_error::Segment::Seq { index } => Some(index.to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn get<'a>(value: &'a Value, keys: &[String]) -> Option<&'a Value> {
+    keys.iter().try_fold(value, |value, key| value.get(key))
+}
+
+// Set the value of a nested key, creating the mappings it is nested in.
+fn set(value: &mut Value, keys: &[String], new: Value) -> Result<(), ObaggError> {
+    let Some((key, rest)) = keys.split_first() else {
+        *value = new;
+        return Ok(());
+    };
+    if value.is_null() {
+        *value = Value::Mapping(Mapping::new());
+    }
+    let Value::Mapping(mapping) = value else {
+        return Err(ObaggError(format!(
+            "cannot set {key} in a value that is not a mapping"
+        )));
+    };
+    let entry = mapping
+        .entry(Value::String(key.clone()))
+        .or_insert(Value::Null);
+    set(entry, rest, new)
+}
+
+impl Default for Exchanges {
+    fn default() -> Self {
+        let exchange = |websocket: &str, api: &str, ping_period| Exchange {
+            api: api.into(),
+            enable: true,
+            websocket: websocket.into(),
+            ping_period,
+            period: None,
+            reconnect_period: None,
+            connections: None,
+            taker_fee: None,
+        };
+        Exchanges {
+            binance: exchange(
+                "wss://stream.binance.com:9443",
+                "https://api.binance.com",
+                10,
+            ),
+            bitstamp: exchange("wss://ws.bitstamp.net", "", 5),
+        }
+    }
+}
+
+// Builds a configuration programmatically, starting from the defaults of conf/obagg.yaml with
+// every optional feature disabled.
+pub struct ServerBuilder {
+    conf: Server,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            conf: Server {
+                bind_address: SocketAddr::from(([127, 0, 0, 1], 50051)),
+                depth: 10,
+                exchanges: Exchanges::default(),
+                identical_level_order: true,
+                fee_adjusted: None,
+                ticker: "ltcbtc".into(),
+                quotes: None,
+                implied: None,
+                trades: None,
+                instruments: None,
+                record: None,
+                history: None,
+                snapshots: None,
+                client_buffer: None,
+                slow_client_policy: None,
+                max_client_lag: None,
+            },
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn bind_address(mut self, bind_address: SocketAddr) -> Self {
+        self.conf.bind_address = bind_address;
+        self
+    }
+
+    pub fn ticker(mut self, ticker: &str) -> Self {
+        self.conf.ticker = ticker.into();
+        self
+    }
+
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.conf.depth = depth;
+        self
+    }
+
+    pub fn identical_level_order(mut self, identical_level_order: bool) -> Self {
+        self.conf.identical_level_order = identical_level_order;
+        self
+    }
+
+    pub fn binance(mut self, binance: Exchange) -> Self {
+        self.conf.exchanges.binance = binance;
+        self
+    }
+
+    pub fn bitstamp(mut self, bitstamp: Exchange) -> Self {
+        self.conf.exchanges.bitstamp = bitstamp;
+        self
+    }
+
+    pub fn trades(mut self, trades: bool) -> Self {
+        self.conf.trades = Some(trades);
+        self
+    }
+
+    // Apply any other setting to the configuration being built.
+    pub fn with(mut self, f: impl FnOnce(&mut Server)) -> Self {
+        f(&mut self.conf);
+        self
+    }
+
+    pub fn build(self) -> Server {
+        self.conf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use crate::fixtures::TempDir;
+
+    #[test]
+    fn env_overrides_are_layered_over_the_file() {
+        let dir = TempDir::new("config");
+        let path = dir.path().join("obagg.yaml");
+        std::fs::write(
+            &path,
+            "
+bind_address: \"127.0.0.1:50051\"
+ticker: ltcbtc
+depth: 10
+identical_level_order: true
+exchanges:
+  binance: { enable: true, websocket: \"wss://stream.binance.com:9443\", api: \"\", ping_period: 10 }
+  bitstamp: { enable: true, websocket: \"wss://ws.bitstamp.net\", api: \"\", ping_period: 5 }
+",
+        )
+        .unwrap();
+        let vars = [
+            ("OBAGG__DEPTH", "50"),
+            ("OBAGG__EXCHANGES__BITSTAMP__ENABLE", "false"),
+            ("OBAGG__EXCHANGES__BINANCE__TAKER_FEE", "0.001"),
+            ("OBAGG__HISTORY__PATH", "/tmp/history"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(var, value)| (var.to_string(), value.to_string()));
+        let conf = Server::from_path(&path, vars).unwrap();
+        assert_eq!(conf.depth, 50);
+        assert_eq!(conf.ticker, "ltcbtc");
+        assert!(!conf.exchanges.bitstamp.enable);
+        assert_eq!(conf.exchanges.binance.taker_fee, Some(0.001));
+        assert_eq!(conf.history.unwrap().path, "/tmp/history");
+
+        let vars = [("OBAGG__TICKER__BASE".to_string(), "ltc".to_string())];
+        let e = Server::from_path(&path, vars).err().unwrap();
+        assert!(e.to_string().contains("OBAGG__TICKER__BASE"));
+        assert!(Server::from_path(&dir.path().join("missing.yaml"), []).is_err());
+
+        // an empty value or one that reads as a number is kept as a string for string keys.
+        let vars = [
+            ("OBAGG__EXCHANGES__BITSTAMP__API", ""),
+            ("OBAGG__TICKER", "1e5"),
+            ("OBAGG__HISTORY__PATH", "2022"),
+            ("OBAGG__DEPTH", "20"),
+        ]
+        .map(|(var, value)| (var.to_string(), value.to_string()));
+        let conf = Server::from_path(&path, vars).unwrap();
+        assert_eq!(conf.exchanges.bitstamp.api, "");
+        assert_eq!(conf.ticker, "1e5");
+        assert_eq!(conf.history.unwrap().path, "2022");
+        assert_eq!(conf.depth, 20);
+
+        let conf = Server::builder().depth(50).trades(true).build();
+        assert_eq!((conf.depth, conf.trades), (50, Some(true)));
+        assert!(conf.exchanges.binance.enable);
+    }
 }
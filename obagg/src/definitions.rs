@@ -2,15 +2,20 @@ use rust_decimal::{prelude::ToPrimitive, Decimal};
 use serde::{self, Deserialize};
 use std::collections::BTreeMap;
 
-use crate::orderbook::Level;
+use crate::orderbook::{Level, Side, Trade};
 
-const BINANCE: &str = "binance";
-const BITSTAMP: &str = "bitstamp";
+pub const BINANCE: &str = "binance";
+pub const BITSTAMP: &str = "bitstamp";
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Orderbook {
     pub bids: BTreeMap<Decimal, Level>,
     pub asks: BTreeMap<Decimal, Level>,
+    // exchange specific, monotonically increasing id of the message the book was built from.
+    pub sequence: u64,
+    // time the message the book was built from was received, in microseconds since the epoch, 0
+    // when unknown.
+    pub received: u64,
 }
 
 impl Orderbook {
@@ -18,18 +23,20 @@ impl Orderbook {
         Self {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
+            sequence: 0,
+            received: 0,
         }
     }
 
     pub fn reduce(&self, depth: usize) -> Self {
         let mut orderbook_reduced = self.clone();
-        if self.bids.len() > usize::from(depth) && self.asks.len() > usize::from(depth) {
+        if self.bids.len() > depth && self.asks.len() > depth {
             let bkeys: Vec<&Decimal> = Vec::from_iter(self.bids.keys());
-            let bkey = bkeys[bkeys.len() - usize::from(depth)].clone();
+            let bkey = *bkeys[bkeys.len() - depth];
             orderbook_reduced.bids = orderbook_reduced.bids.split_off(&bkey);
 
             let akeys: Vec<&Decimal> = Vec::from_iter(self.asks.keys());
-            let akey = akeys[usize::from(depth)].clone();
+            let akey = *akeys[depth];
             orderbook_reduced.asks.split_off(&akey);
         }
         orderbook_reduced
@@ -40,6 +47,49 @@ impl Orderbook {
 pub enum Orderbooks {
     Binance(Orderbook),
     Bitstamp(Orderbook),
+    // orderbook of an auxiliary market, e.g. a quote or rate market used for normalization.
+    Market {
+        exchange: &'static str,
+        ticker: String,
+        orderbook: Orderbook,
+    },
+}
+
+// Messages of the feeds to the aggregator, the books of the markets and the trades of the
+// exchanges in the order they were received.
+#[derive(Clone, Debug)]
+pub enum MarketData {
+    Book(Orderbooks),
+    // a trade executed on an exchange.
+    Trade(Trade),
+}
+
+// Events pushed from a single websocket connection to the feed supervisor of its exchange.
+#[derive(Clone, Debug)]
+pub enum FeedEvent {
+    Orderbook(Orderbook),
+    Trade(Trade),
+    // the exchange asked us to move to a new connection (e.g. bitstamp bts:request_reconnect).
+    Reconnect,
+    // the connection terminated, sent by the supervisor on behalf of the consumer.
+    Closed,
+}
+
+impl FeedEvent {
+    // exchange specific, monotonically increasing sequence of the books and trades of a feed.
+    pub fn sequence(&self) -> u64 {
+        match self {
+            FeedEvent::Orderbook(orderbook) => orderbook.sequence,
+            FeedEvent::Trade(trade) => trade.trade_id,
+            FeedEvent::Reconnect | FeedEvent::Closed => 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BitstampEventMessage {
+    pub event: String,
+    pub channel: String,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -86,6 +136,76 @@ pub struct BinanceOrderbookUpdateMessage {
     pub asks: Vec<OrderbookLevel>,
 }
 
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BitstampTradeMessage {
+    pub data: BitstampTradeData,
+    pub channel: String,
+    pub event: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BitstampTradeData {
+    pub id: u64,
+    pub amount: f64,
+    pub price: f64,
+    // 0 for buy, 1 for sell.
+    #[serde(rename = "type")]
+    pub side: u8,
+    #[serde(deserialize_with = "crate::serde::u64_from_str")]
+    pub microtimestamp: u64,
+}
+
+impl From<BitstampTradeData> for Trade {
+    fn from(data: BitstampTradeData) -> Self {
+        Trade {
+            exchange: BITSTAMP.into(),
+            price: data.price,
+            amount: data.amount,
+            side: match data.side {
+                0 => Side::Buy,
+                _ => Side::Sell,
+            } as i32,
+            trade_id: data.id,
+            timestamp: data.microtimestamp,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BinanceTradeMessage {
+    #[serde(rename = "e")]
+    pub event: String,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "t")]
+    pub trade_id: u64,
+    #[serde(rename = "p", deserialize_with = "crate::serde::f64_from_str")]
+    pub price: f64,
+    #[serde(rename = "q", deserialize_with = "crate::serde::f64_from_str")]
+    pub quantity: f64,
+    // trade time in milliseconds.
+    #[serde(rename = "T")]
+    pub trade_time: u64,
+    #[serde(rename = "m")]
+    pub buyer_is_maker: bool,
+}
+
+impl From<BinanceTradeMessage> for Trade {
+    fn from(message: BinanceTradeMessage) -> Self {
+        Trade {
+            exchange: BINANCE.into(),
+            price: message.price,
+            amount: message.quantity,
+            side: match message.buyer_is_maker {
+                true => Side::Sell,
+                false => Side::Buy,
+            } as i32,
+            trade_id: message.trade_id,
+            timestamp: message.trade_time * 1000,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ExchangeOrderbookLevel {
     Binance(OrderbookLevel),
@@ -119,6 +239,7 @@ impl From<ExchangeOrderbookLevel> for Level {
             amount: obl.amount(),
             exchange: obl.exchange(),
             price: obl.price().to_f64().unwrap_or(0.0),
+            ..Default::default()
         }
     }
 }
@@ -140,16 +261,23 @@ impl OrderbookLevel {
     }
 }
 
+// the tests are kept as written before clippy and rustfmt were enforced.
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
+    #[rustfmt::skip]
     use rust_decimal::Decimal;
     use rust_decimal::prelude::FromPrimitive;
 
     use super::BinanceOrderbookMessage;
     use super::BinanceOrderbookUpdateMessage;
+    use super::BinanceTradeMessage;
+    use super::BitstampEventMessage;
     use super::BitstampOrderbookData;
     use super::BitstampOrderbookMessage;
+    use super::BitstampTradeMessage;
     use super::OrderbookLevel;
+    use crate::orderbook::{Side, Trade};
 
     #[test]
     fn bitstamp_oderbook_message() {
@@ -186,6 +314,22 @@ mod tests {
         assert_eq!(deserialized_orderbook, bitstamp_orderbook_message);
     }
 
+    #[test]
+    fn bitstamp_request_reconnect_message() {
+        let json_message = r#"{
+                "event":"bts:request_reconnect",
+                "channel":"",
+                "data":""
+            }"#;
+        let bitstamp_event_message = BitstampEventMessage {
+            event: String::from("bts:request_reconnect"),
+            channel: String::new(),
+        };
+        let deserialized_event =
+            serde_json::from_str::<BitstampEventMessage>(json_message).unwrap();
+        assert_eq!(deserialized_event, bitstamp_event_message);
+    }
+
     #[test]
     fn binance_oderbook_message() {
         let json_message = r#"{
@@ -239,4 +383,63 @@ mod tests {
             serde_json::from_str::<BinanceOrderbookUpdateMessage>(&json_message).unwrap();
         assert_eq!(deserialized_orderbook, binance_orderbook_update_message);
     }
+
+    #[test]
+    fn binance_trade_message() {
+        let json_message = r#"{
+                "e":"trade",
+                "E":1661586147639,
+                "s":"LTCBTC",
+                "t":81234567,
+                "p":"0.00259978",
+                "q":"4.35000000",
+                "b":88,
+                "a":50,
+                "T":1661586147638,
+                "m":true,
+                "M":true
+            }"#;
+        let trade: Trade = serde_json::from_str::<BinanceTradeMessage>(json_message)
+            .unwrap()
+            .into();
+        assert_eq!(
+            trade,
+            Trade {
+                exchange: String::from("binance"),
+                price: 0.00259978,
+                amount: 4.35,
+                side: Side::Sell as i32,
+                trade_id: 81234567,
+                timestamp: 1661586147638000,
+            }
+        );
+    }
+
+    #[test]
+    fn bitstamp_trade_message() {
+        let json_message = r#"{
+                "data":{
+                    "id":250124011,
+                    "timestamp":"1661585367",
+                    "amount":4.35,
+                    "amount_str":"4.35000000",
+                    "price":0.00259978,
+                    "price_str":"0.00259978",
+                    "type":0,
+                    "microtimestamp":"1661585367425575",
+                    "buy_order_id":1525870464524288,
+                    "sell_order_id":1525870453309440
+                },
+                "channel":"live_trades_ltcbtc",
+                "event":"trade"
+            }"#;
+        let trade: Trade = serde_json::from_str::<BitstampTradeMessage>(json_message)
+            .unwrap()
+            .data
+            .into();
+        assert_eq!(trade.exchange, "bitstamp");
+        assert_eq!(trade.side, Side::Buy as i32);
+        assert_eq!(trade.trade_id, 250124011);
+        assert_eq!(trade.timestamp, 1661585367425575);
+    }
 }
@@ -0,0 +1,476 @@
+use log::{error, info, warn};
+use std::{error::Error, future::Future};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep_until, Duration, Instant},
+};
+use tokio_tungstenite::tungstenite::Message;
+use tonic::Status;
+
+use crate::{
+    capture::frame::Kind,
+    definitions::{FeedEvent, MarketData, Orderbook, Orderbooks},
+    recorder::Tap,
+    store::StatusLog,
+    utils,
+};
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Handle given to a websocket consumer so that the supervisor knows which connection an event
+// came from.
+#[derive(Clone)]
+pub struct FeedSender {
+    id: usize,
+    tx: mpsc::Sender<(usize, FeedEvent)>,
+    tap: Option<Tap>,
+}
+
+impl FeedSender {
+    // Capture an inbound frame of the connection, when the feed is recorded.
+    pub fn record(&self, kind: Kind, payload: &str) {
+        if let Some(tap) = &self.tap {
+            tap.record(self.id, kind, payload);
+        }
+    }
+
+    // Capture an inbound websocket message of the connection, whatever its type.
+    pub fn record_message(&self, message: &Message) {
+        if let Some(tap) = &self.tap {
+            tap.record_message(self.id, message);
+        }
+    }
+
+    pub async fn send(&self, mut event: FeedEvent) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // books carry the time they were received, as the frames they were built from.
+        if let FeedEvent::Orderbook(orderbook) = &mut event {
+            orderbook.received = utils::now_micros();
+        }
+        self.tx
+            .send((self.id, event))
+            .await
+            .map_err(|e| e.to_string().into())
+    }
+}
+
+// Optional observers of a feed: the recorder of the inbound frames of its connections and the log
+// of its status transitions.
+#[derive(Clone, Default)]
+pub struct Observers {
+    pub tap: Option<Tap>,
+    pub status: Option<StatusLog>,
+}
+
+// A running websocket consumer. Dropping the connection aborts its task, which in turn drops and
+// closes the underlying socket.
+struct Connection {
+    id: usize,
+    handle: JoinHandle<()>,
+    // id of the connection that is closed once this one has synced.
+    replaces: Option<usize>,
+    reconnect_at: Option<Instant>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+struct Pool<C> {
+    name: &'static str,
+    connect: C,
+    events_tx: mpsc::Sender<(usize, FeedEvent)>,
+    tap: Option<Tap>,
+    reconnect_period: Option<Duration>,
+    next_id: usize,
+    connections: Vec<Connection>,
+}
+
+impl<C, F> Pool<C>
+where
+    C: Fn(FeedSender) -> F,
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+{
+    fn open(&mut self, replaces: Option<usize>) {
+        let name = self.name;
+        let id = self.next_id;
+        self.next_id += 1;
+        info!("Opening {} websocket connection {}.", name, id);
+        let feed_tx = FeedSender {
+            id,
+            tx: self.events_tx.clone(),
+            tap: self.tap.clone(),
+        };
+        let future = (self.connect)(feed_tx.clone());
+        let handle = tokio::spawn(async move {
+            if let Err(e) = future.await {
+                error!("{} websocket connection {} failed : {}", name, id, e);
+            }
+            let _ = feed_tx.send(FeedEvent::Closed).await;
+        });
+        if let Some(old) = replaces.and_then(|old| self.get_mut(old)) {
+            old.reconnect_at = None;
+        }
+        self.connections.push(Connection {
+            id,
+            handle,
+            replaces,
+            reconnect_at: self.reconnect_period.map(|p| Instant::now() + p),
+        });
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut Connection> {
+        self.connections.iter_mut().find(|c| c.id == id)
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Connection> {
+        let index = self.connections.iter().position(|c| c.id == id)?;
+        Some(self.connections.remove(index))
+    }
+
+    fn replacement_of(&mut self, id: usize) -> Option<&mut Connection> {
+        self.connections.iter_mut().find(|c| c.replaces == Some(id))
+    }
+
+    // number of connections that are not temporary replacements of another.
+    fn slots(&self) -> usize {
+        self.connections
+            .iter()
+            .filter(|c| c.replaces.is_none())
+            .count()
+    }
+
+    fn next_reconnect(&self) -> Option<Instant> {
+        self.connections.iter().filter_map(|c| c.reconnect_at).min()
+    }
+}
+
+// Supervise the websocket connections of a single exchange book feed and forward their orderbooks,
+// wrapped as the market they are the books of, to the aggregator.
+pub async fn supervise<C, F, W>(
+    name: &'static str,
+    connect: C,
+    observers: Observers,
+    connections: usize,
+    reconnect_period: Option<Duration>,
+    wrap: W,
+    tx: mpsc::Sender<Result<MarketData, Status>>,
+) where
+    C: Fn(FeedSender) -> F,
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+    W: Fn(Orderbook) -> Orderbooks,
+{
+    let forward = |event| match event {
+        FeedEvent::Orderbook(orderbook) => Some(MarketData::Book(wrap(orderbook))),
+        _ => None,
+    };
+    run(
+        name,
+        connect,
+        observers,
+        connections,
+        reconnect_period,
+        forward,
+        tx,
+    )
+    .await
+}
+
+// Supervise the websocket connections of a single exchange trade feed and forward their trades to
+// the aggregator.
+pub async fn supervise_trades<C, F>(
+    name: &'static str,
+    connect: C,
+    observers: Observers,
+    connections: usize,
+    reconnect_period: Option<Duration>,
+    tx: mpsc::Sender<Result<MarketData, Status>>,
+) where
+    C: Fn(FeedSender) -> F,
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+{
+    let forward = |event| match event {
+        FeedEvent::Trade(trade) => Some(MarketData::Trade(trade)),
+        _ => None,
+    };
+    run(
+        name,
+        connect,
+        observers,
+        connections,
+        reconnect_period,
+        forward,
+        tx,
+    )
+    .await
+}
+
+// Supervise the connections of a feed, forwarding the updates they deliver as converted by
+// forward.
+//
+// Hot-standby: the supervisor keeps `connections` independent websockets open to the same feed.
+// Orderbooks and trades are deduplicated by their sequence, so whichever connection delivers an
+// update first is forwarded and a dropped connection fails over instantly to the others.
+//
+// Reconnects requested by the exchange, or scheduled through the reconnect_period, are performed
+// make-before-break:
+// 1. Open a replacement connection while the current one keeps feeding the aggregator.
+// 2. Wait for the replacement to deliver a synced orderbook that is at least as recent as the last
+//    one forwarded.
+// 3. Close the old connection.
+//
+// When a tap is given every inbound frame of every connection is recorded. When a status log is
+// given the feed is logged up on the first update it forwards, and down once its last connection
+// closed.
+async fn run<C, F, W>(
+    name: &'static str,
+    connect: C,
+    observers: Observers,
+    connections: usize,
+    reconnect_period: Option<Duration>,
+    forward: W,
+    tx: mpsc::Sender<Result<MarketData, Status>>,
+) where
+    C: Fn(FeedSender) -> F,
+    F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send + 'static,
+    W: Fn(FeedEvent) -> Option<MarketData>,
+{
+    let (events_tx, mut events_rx) = mpsc::channel::<(usize, FeedEvent)>(1024);
+    let connections = connections.max(1);
+    let mut pool = Pool {
+        name,
+        connect,
+        events_tx,
+        tap: observers.tap,
+        reconnect_period,
+        next_id: 0,
+        connections: vec![],
+    };
+    for _ in 0..connections {
+        pool.open(None);
+    }
+    let mut relaunch_at: Option<Instant> = None;
+    let mut last_sequence = 0;
+    let mut up = false;
+
+    loop {
+        let deadline = pool.next_reconnect().into_iter().chain(relaunch_at).min();
+        tokio::select! {
+            Some((id, event)) = events_rx.recv() => match event {
+                event @ (FeedEvent::Orderbook(_) | FeedEvent::Trade(_)) => {
+                    let Some(connection) = pool.get_mut(id) else {
+                        continue;
+                    };
+                    // updates the feed does not forward are not counted as delivered.
+                    let sequence = event.sequence();
+                    let Some(item) = forward(event) else {
+                        continue;
+                    };
+                    if sequence >= last_sequence {
+                        if let Some(old) = connection.replaces.take() {
+                            // the replacement has caught up, close the connection it replaces.
+                            info!(
+                                "Switching {} book source from connection {} to {}.",
+                                name, old, id
+                            );
+                            pool.remove(old);
+                        }
+                    }
+                    // drop updates that another connection has already delivered.
+                    if sequence <= last_sequence {
+                        continue;
+                    }
+                    last_sequence = sequence;
+                    if !up {
+                        up = true;
+                        if let Some(status) = &observers.status {
+                            status.record(true);
+                        }
+                    }
+                    if let Err(_item) = tx.send(Ok(item)).await {
+                        error!("Error sending {} orderbook item.", name);
+                    }
+                }
+                FeedEvent::Reconnect => {
+                    if pool.get_mut(id).is_some() && pool.replacement_of(id).is_none() {
+                        warn!("{} requested a reconnect of connection {}.", name, id);
+                        pool.open(Some(id));
+                    }
+                }
+                FeedEvent::Closed => {
+                    let Some(connection) = pool.remove(id) else {
+                        continue;
+                    };
+                    warn!("{} websocket connection {} closed.", name, id);
+                    if up && pool.connections.is_empty() {
+                        up = false;
+                        if let Some(status) = &observers.status {
+                            status.record(false);
+                        }
+                    }
+                    if let Some(old) = connection.replaces {
+                        // the replacement failed before syncing, retry the reconnect later.
+                        if let Some(old) = pool.get_mut(old) {
+                            old.reconnect_at = Some(Instant::now() + RETRY_DELAY);
+                        }
+                    } else if let Some(replacement) = pool.replacement_of(id) {
+                        replacement.replaces = None;
+                    } else if relaunch_at.is_none() {
+                        relaunch_at = Some(Instant::now() + RETRY_DELAY);
+                    }
+                }
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let now = Instant::now();
+                if relaunch_at.is_some_and(|t| t <= now) {
+                    relaunch_at = None;
+                    while pool.slots() < connections {
+                        warn!("Relaunching {} websocket consumer.", name);
+                        pool.open(None);
+                    }
+                }
+                let due: Vec<usize> = pool
+                    .connections
+                    .iter()
+                    .filter(|c| c.reconnect_at.is_some_and(|t| t <= now))
+                    .map(|c| c.id)
+                    .collect();
+                for id in due {
+                    info!("Scheduled reconnect of {} connection {}.", name, id);
+                    pool.open(Some(id));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::{
+        sync::mpsc,
+        time::{sleep, timeout, Duration},
+    };
+    use tonic::Status;
+
+    use crate::{
+        definitions::{FeedEvent, MarketData, Orderbook, Orderbooks},
+        orderbook::Trade,
+    };
+
+    fn orderbook(sequence: u64) -> FeedEvent {
+        let mut orderbook = Orderbook::new();
+        orderbook.sequence = sequence;
+        FeedEvent::Orderbook(orderbook)
+    }
+
+    async fn sequences(rx: &mut mpsc::Receiver<Result<MarketData, Status>>, n: usize) -> Vec<u64> {
+        let mut out = vec![];
+        for _ in 0..n {
+            match timeout(Duration::from_secs(5), rx.recv()).await {
+                Ok(Some(Ok(MarketData::Book(Orderbooks::Binance(ob))))) => out.push(ob.sequence),
+                _ => break,
+            }
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn hot_standby_dedupes_by_sequence() {
+        let (tx, mut rx) = mpsc::channel(1024);
+        let opened = Arc::new(AtomicUsize::new(0));
+        let scripts = [vec![1, 2, 3], vec![2, 3, 4, 5]];
+        tokio::spawn(super::supervise(
+            "binance",
+            move |feed| {
+                let script = scripts[opened.fetch_add(1, Ordering::SeqCst) % 2].clone();
+                async move {
+                    for sequence in script {
+                        feed.send(orderbook(sequence)).await?;
+                        sleep(Duration::from_millis(10)).await;
+                    }
+                    // keep the connection open.
+                    sleep(Duration::from_secs(60)).await;
+                    Ok(())
+                }
+            },
+            Default::default(),
+            2,
+            None,
+            Orderbooks::Binance,
+            tx,
+        ));
+        assert_eq!(sequences(&mut rx, 5).await, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn reconnect_is_make_before_break() {
+        let (tx, mut rx) = mpsc::channel(1024);
+        let opened = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(super::supervise(
+            "bitstamp",
+            move |feed| {
+                let id = opened.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if id == 0 {
+                        feed.send(orderbook(1)).await?;
+                        feed.send(FeedEvent::Reconnect).await?;
+                        // the old connection keeps delivering until the new one has synced.
+                        sleep(Duration::from_millis(50)).await;
+                        feed.send(orderbook(2)).await?;
+                        sleep(Duration::from_secs(60)).await;
+                    } else {
+                        sleep(Duration::from_millis(100)).await;
+                        feed.send(orderbook(3)).await?;
+                        feed.send(orderbook(4)).await?;
+                        sleep(Duration::from_secs(60)).await;
+                    }
+                    Ok(())
+                }
+            },
+            Default::default(),
+            1,
+            None,
+            Orderbooks::Binance,
+            tx,
+        ));
+        assert_eq!(sequences(&mut rx, 4).await, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn trade_feed_forwards_trades_only() {
+        let (tx, mut rx) = mpsc::channel(1024);
+        tokio::spawn(super::supervise_trades(
+            "binance",
+            move |feed| async move {
+                for trade_id in [1, 2] {
+                    feed.send(orderbook(trade_id + 10)).await?;
+                    let trade = Trade {
+                        trade_id,
+                        ..Default::default()
+                    };
+                    feed.send(FeedEvent::Trade(trade)).await?;
+                }
+                sleep(Duration::from_secs(60)).await;
+                Ok(())
+            },
+            Default::default(),
+            1,
+            None,
+            tx,
+        ));
+        let mut trades = vec![];
+        while let Ok(Some(Ok(item))) = timeout(Duration::from_millis(200), rx.recv()).await {
+            match item {
+                MarketData::Trade(trade) => trades.push(trade.trade_id),
+                MarketData::Book(_) => panic!("a trade feed forwarded a book"),
+            }
+        }
+        assert_eq!(trades, vec![1, 2]);
+    }
+}
@@ -0,0 +1,48 @@
+// Fixtures shared by the tests.
+use std::path::{Path, PathBuf};
+
+use crate::orderbook::{Level, Summary};
+
+// Directory of a test under the system temporary directory, emptied when created and removed once
+// dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("obagg-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    // Path of a file or directory in the directory, as found in a configuration.
+    pub fn join(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Summary of a book with a single binance level on each side, asked at 0.001.
+pub fn summary(bid: f64) -> Summary {
+    let level = |price| Level {
+        exchange: "binance".into(),
+        price,
+        amount: 1.5,
+        ..Default::default()
+    };
+    Summary {
+        spread: 0.001 - bid,
+        bids: vec![level(bid)],
+        asks: vec![level(0.001)],
+        ..Default::default()
+    }
+}
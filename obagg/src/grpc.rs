@@ -4,40 +4,63 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 use tokio::sync::{mpsc, RwLock};
-use tokio_stream::Stream;
+use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status};
 use uuid::Uuid;
 
 use crate::{
+    aggregator::{self, Outputs},
+    candles, config, impact,
+    metrics::Metrics,
     orderbook,
-    orderbook::{Empty, Summary},
+    orderbook::{
+        ArbitrageOpportunity, Bbo, BookAtRequest, BookSnapshot, Candle, CandleRequest, Empty,
+        ImpactRequest, ImpactResponse, RoutePlan, RouteRequest, ServerMetrics, Summary,
+        SummaryRequest, Trade,
+    },
+    routing,
+    store::Store,
+    subscriber::{broadcast_stream, Subscriber, SummaryStream},
+    view::View,
 };
 
 type OrderbookAggregatorResult<T> = Result<Response<T>, Status>;
 type ResponseStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send>>;
-type ProducerPool = Arc<RwLock<HashMap<Uuid, mpsc::Sender<Result<Summary, Status>>>>>;
+type ArbitrageResponseStream =
+    Pin<Box<dyn Stream<Item = Result<ArbitrageOpportunity, Status>> + Send>>;
+type BboResponseStream = Pin<Box<dyn Stream<Item = Result<Bbo, Status>> + Send>>;
+type TradeResponseStream = Pin<Box<dyn Stream<Item = Result<Trade, Status>> + Send>>;
+type CandleResponseStream = Pin<Box<dyn Stream<Item = Result<Candle, Status>> + Send>>;
+pub(crate) type ProducerPool = Arc<RwLock<HashMap<Uuid, Subscriber>>>;
 
-#[derive(Debug)]
 pub struct OrderbookAggregatorServer {
+    pub conf: config::Server,
     pub tx_pool: ProducerPool,
+    pub outputs: Arc<Outputs>,
+    pub store: Option<Store>,
+    pub metrics: Arc<Metrics>,
 }
 
-struct DropReceiver<T> {
+struct DropReceiver {
     chan: mpsc::UnboundedSender<usize>,
-    inner: mpsc::Receiver<T>,
+    inner: SummaryStream,
 }
 
-impl<T> Stream for DropReceiver<T> {
-    type Item = T;
+impl Stream for DropReceiver {
+    type Item = Result<Summary, Status>;
 
-    fn poll_next(mut self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
-        self.inner.poll_recv(cx)
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
     }
 }
 
-impl<T> Drop for DropReceiver<T> {
+impl Drop for DropReceiver {
     fn drop(&mut self) {
         self.chan.send(1).unwrap();
     }
@@ -46,12 +69,25 @@ impl<T> Drop for DropReceiver<T> {
 #[tonic::async_trait]
 impl orderbook::orderbook_aggregator_server::OrderbookAggregator for OrderbookAggregatorServer {
     type BookSummaryStreamStream = ResponseStream;
+    type ArbitrageStreamStream = ArbitrageResponseStream;
+    type BboStreamStream = BboResponseStream;
+    type TradeStreamStream = TradeResponseStream;
+    type CandleStreamStream = CandleResponseStream;
     async fn book_summary_stream(
         &self,
-        req: Request<Empty>,
+        req: Request<SummaryRequest>,
     ) -> OrderbookAggregatorResult<Self::BookSummaryStreamStream> {
         info!("New gRPC client connected from: {:?}", req.remote_addr());
-        let (tx, rx) = mpsc::channel(1024);
+        let min_interval = match req.get_ref().min_interval.clone() {
+            Some(interval) => Some(
+                Duration::try_from(interval)
+                    .map_err(|e| Status::invalid_argument(format!("Invalid min_interval : {e}")))?,
+            ),
+            None => None,
+        };
+        let view = View::from_request(req.get_ref()).map_err(|e| Status::invalid_argument(e.0))?;
+        let (subscriber, rx) =
+            Subscriber::new(self.conf.client_buffer.unwrap_or(1024), min_interval, view);
         let (oneshot_tx, mut oneshot_rx) = mpsc::unbounded_channel::<usize>();
         let tx_pool_pop = self.tx_pool.clone();
         let id = Uuid::new_v4();
@@ -61,7 +97,7 @@ impl orderbook::orderbook_aggregator_server::OrderbookAggregator for OrderbookAg
                 "Spawned drop handler thread for gRPC producer pool id : {}",
                 &id
             );
-            while let Some(_) = oneshot_rx.recv().await {
+            while oneshot_rx.recv().await.is_some() {
                 let mut tx_pool = tx_pool_pop.write().await;
                 info!(
                     "gRPC client connection closed, remove producer pool entry: {}",
@@ -74,7 +110,7 @@ impl orderbook::orderbook_aggregator_server::OrderbookAggregator for OrderbookAg
         {
             let mut tx_pool = self.tx_pool.write().await;
             info!("Add a new gRPC producer pool entry with id {}", &id);
-            tx_pool.insert(id, tx.clone());
+            tx_pool.insert(id, subscriber);
         }
 
         let output_stream = DropReceiver {
@@ -85,4 +121,102 @@ impl orderbook::orderbook_aggregator_server::OrderbookAggregator for OrderbookAg
         let res = Response::new(Box::pin(output_stream) as Self::BookSummaryStreamStream);
         Ok(res)
     }
+
+    async fn market_impact(
+        &self,
+        req: Request<ImpactRequest>,
+    ) -> OrderbookAggregatorResult<ImpactResponse> {
+        let req = req.into_inner();
+        impact::validate(&req).map_err(|e| Status::invalid_argument(e.0))?;
+        let impact = impact::market_impact(&*self.outputs.book.read().await, &req)
+            .map_err(|e| Status::failed_precondition(e.0))?;
+        Ok(Response::new(impact))
+    }
+
+    async fn plan_route(&self, req: Request<RouteRequest>) -> OrderbookAggregatorResult<RoutePlan> {
+        let req = req.into_inner();
+        routing::validate(&req).map_err(|e| Status::invalid_argument(e.0))?;
+        let plan = routing::plan(&*self.outputs.book.read().await, &req, &self.conf)
+            .map_err(|e| Status::failed_precondition(e.0))?;
+        Ok(Response::new(plan))
+    }
+
+    async fn arbitrage_stream(
+        &self,
+        req: Request<Empty>,
+    ) -> OrderbookAggregatorResult<Self::ArbitrageStreamStream> {
+        info!(
+            "New arbitrage client connected from: {:?}",
+            req.remote_addr()
+        );
+        let stream = broadcast_stream(self.outputs.arbitrage.subscribe(), "Arbitrage");
+        Ok(Response::new(
+            Box::pin(stream) as Self::ArbitrageStreamStream
+        ))
+    }
+
+    async fn bbo_stream(
+        &self,
+        req: Request<Empty>,
+    ) -> OrderbookAggregatorResult<Self::BboStreamStream> {
+        info!("New BBO client connected from: {:?}", req.remote_addr());
+        let stream = aggregator::bbo_stream(self.outputs.bbo.subscribe());
+        Ok(Response::new(Box::pin(stream) as Self::BboStreamStream))
+    }
+
+    async fn trade_stream(
+        &self,
+        req: Request<Empty>,
+    ) -> OrderbookAggregatorResult<Self::TradeStreamStream> {
+        info!("New trade client connected from: {:?}", req.remote_addr());
+        if !self.conf.trades.unwrap_or(false) {
+            return Err(Status::failed_precondition(
+                "Trade ingestion is not enabled on this server",
+            ));
+        }
+        let stream = broadcast_stream(self.outputs.trades.subscribe(), "Trade");
+        Ok(Response::new(Box::pin(stream) as Self::TradeStreamStream))
+    }
+
+    async fn candle_stream(
+        &self,
+        req: Request<CandleRequest>,
+    ) -> OrderbookAggregatorResult<Self::CandleStreamStream> {
+        info!("New candle client connected from: {:?}", req.remote_addr());
+        let req = req.into_inner();
+        candles::validate(&req).map_err(|e| Status::invalid_argument(e.0))?;
+        // the history is locked while subscribing so no bar is missed or sent twice.
+        let (backfill, rx) = self.outputs.candles.lock().await.subscribe(&req);
+        let live = broadcast_stream(rx, "Candle")
+            .filter(move |candle| candle.as_ref().map_or(true, |c| candles::matches(&req, c)));
+        let stream = tokio_stream::iter(backfill.into_iter().map(Ok)).chain(live);
+        Ok(Response::new(Box::pin(stream) as Self::CandleStreamStream))
+    }
+
+    async fn get_book_at(
+        &self,
+        req: Request<BookAtRequest>,
+    ) -> OrderbookAggregatorResult<BookSnapshot> {
+        let req = req.into_inner();
+        let Some(store) = &self.store else {
+            return Err(Status::failed_precondition(
+                "Snapshots are not stored on this server",
+            ));
+        };
+        let snapshot = store
+            .book_at(&req.symbol, req.timestamp)
+            .await
+            .map_err(|e| Status::internal(format!("Error reading snapshots : {e}")))?;
+        match snapshot {
+            Some(snapshot) => Ok(Response::new(snapshot)),
+            None => Err(Status::not_found(format!(
+                "No snapshot of {} is stored",
+                req.symbol
+            ))),
+        }
+    }
+
+    async fn get_metrics(&self, _: Request<Empty>) -> OrderbookAggregatorResult<ServerMetrics> {
+        Ok(Response::new(self.metrics.snapshot()))
+    }
 }
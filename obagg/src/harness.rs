@@ -0,0 +1,155 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, RwLock},
+    task::JoinHandle,
+    time::{sleep, timeout, Duration},
+};
+use tonic::{transport::Channel, Status, Streaming};
+
+use crate::{
+    binance, bitstamp, config,
+    definitions::{MarketData, Orderbooks},
+    grpc::ProducerPool,
+    orderbook::{orderbook_aggregator_client::OrderbookAggregatorClient, Summary, SummaryRequest},
+    server,
+};
+
+// End-to-end harness: the server runs in process on an ephemeral port and real tonic clients
+// connect to it. The whole server consumes the exchanges of the configuration, e.g. mock
+// exchanges, or the books are injected in place of the exchange feeds.
+pub struct Harness {
+    pub addr: SocketAddr,
+    depth: usize,
+    // input of the aggregator, when the books are injected.
+    feed: Option<mpsc::Sender<Result<MarketData, Status>>>,
+    tx_pool: ProducerPool,
+    server: JoinHandle<()>,
+}
+
+impl Harness {
+    // Run the whole server, feeds included.
+    pub async fn start_server(conf: config::Server) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tx_pool: ProducerPool = Arc::new(RwLock::new(HashMap::new()));
+        let pool = tx_pool.clone();
+        let depth = conf.depth;
+        let server = tokio::spawn(async move {
+            server::server_with_listener(conf, listener, pool)
+                .await
+                .unwrap();
+        });
+        Harness {
+            addr,
+            depth,
+            feed: None,
+            tx_pool,
+            server,
+        }
+    }
+
+    // Run the aggregator and the gRPC server, the books are injected with the send methods.
+    pub async fn start(conf: config::Server) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (feed, rx) = mpsc::channel(1024);
+        let tx_pool: ProducerPool = Arc::new(RwLock::new(HashMap::new()));
+        let server_future = server::serve(&conf, listener, rx, tx_pool.clone(), None).unwrap();
+        let server = tokio::spawn(async move {
+            server_future.await.unwrap();
+        });
+        Harness {
+            addr,
+            depth: conf.depth,
+            feed: Some(feed),
+            tx_pool,
+            server,
+        }
+    }
+
+    pub async fn client(&self) -> OrderbookAggregatorClient<Channel> {
+        OrderbookAggregatorClient::connect(format!("http://{}", self.addr))
+            .await
+            .unwrap()
+    }
+
+    // Open a book summary stream, once the server has added the client to the producer pool.
+    pub async fn subscribe(&self, req: SummaryRequest) -> Streaming<Summary> {
+        let clients = self.clients().await;
+        let stream = self
+            .client()
+            .await
+            .book_summary_stream(req)
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(self.until_clients(clients + 1).await);
+        stream
+    }
+
+    // Inject the book of a binance partial book depth message.
+    pub async fn send_binance(&self, message: &str) {
+        let orderbook = binance::parse_reduced_orderbook(message).unwrap();
+        self.send(Orderbooks::Binance(orderbook)).await;
+    }
+
+    // Inject the book of a bitstamp order book message.
+    pub async fn send_bitstamp(&self, message: &str) {
+        let orderbook = bitstamp::parse_orderbook(message, self.depth).unwrap();
+        self.send(Orderbooks::Bitstamp(orderbook)).await;
+    }
+
+    // Inject the book of a binance partial book depth message for another market.
+    pub async fn send_market(&self, exchange: &'static str, ticker: &str, message: &str) {
+        let orderbook = binance::parse_reduced_orderbook(message).unwrap();
+        self.send(Orderbooks::Market {
+            exchange,
+            ticker: ticker.into(),
+            orderbook,
+        })
+        .await;
+    }
+
+    // Inject a book as if an exchange feed had delivered it.
+    async fn send(&self, orderbooks: Orderbooks) {
+        let feed = self
+            .feed
+            .as_ref()
+            .expect("books are injected in a harness started with start");
+        feed.send(Ok(MarketData::Book(orderbooks))).await.unwrap();
+    }
+
+    // Number of clients in the producer pool.
+    pub async fn clients(&self) -> usize {
+        self.tx_pool.read().await.len()
+    }
+
+    // Wait for the producer pool to hold n clients, returns whether it did within 5 seconds.
+    pub async fn until_clients(&self, n: usize) -> bool {
+        let waited = timeout(Duration::from_secs(5), async {
+            while self.clients().await != n {
+                sleep(Duration::from_millis(10)).await;
+            }
+        });
+        waited.await.is_ok()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+// The next n summaries of a stream, fewer when they do not arrive in time.
+pub async fn summaries(stream: &mut Streaming<Summary>, n: usize) -> Vec<Summary> {
+    let mut out = vec![];
+    for _ in 0..n {
+        match timeout(Duration::from_secs(5), stream.message()).await {
+            Ok(Ok(Some(summary))) => out.push(summary),
+            _ => break,
+        }
+    }
+    out
+}
@@ -0,0 +1,234 @@
+use log::{error, info, warn};
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{config, orderbook::Summary};
+
+const HEADER: &str = "timestamp,sequence,symbol,side,level,exchange,price,amount";
+// Summaries waiting to be written, beyond which new ones are dropped rather than slowing down the
+// aggregator, unless the history is lossless.
+const QUEUE: usize = 4096;
+
+struct Record {
+    // time the message the book was aggregated from was received, in microseconds since the epoch.
+    timestamp: u64,
+    sequence: u64,
+    summary: Summary,
+}
+
+// Handle to the history writer, which writes the aggregated books to CSV files partitioned by
+// date and hour.
+pub struct History {
+    tx: mpsc::Sender<Record>,
+    // the aggregator waits on a full queue rather than dropping books, as when rebuilding the
+    // history from captures.
+    lossless: bool,
+    writer: JoinHandle<()>,
+}
+
+impl History {
+    pub fn spawn(
+        conf: &config::History,
+        symbol: &str,
+        lossless: bool,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        fs::create_dir_all(&conf.path)?;
+        let mut writer = HistoryWriter {
+            dir: PathBuf::from(&conf.path),
+            symbol: symbol.into(),
+            interval: conf.interval.unwrap_or(0) * 1000,
+            file: None,
+            last: None,
+        };
+        let (tx, mut rx) = mpsc::channel::<Record>(QUEUE);
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(record) = rx.blocking_recv() {
+                let mut result = writer.write(&record);
+                while let (Ok(()), Ok(record)) = (&result, rx.try_recv()) {
+                    result = writer.write(&record);
+                }
+                if let Err(e) = result.and_then(|_| writer.flush()) {
+                    error!("Error writing history file, history stopped : {}", e);
+                    return;
+                }
+            }
+        });
+        Ok(History {
+            tx,
+            lossless,
+            writer,
+        })
+    }
+
+    // Queue the summary of the aggregated book with the given sequence, aggregated from a message
+    // received at timestamp.
+    pub async fn record(&self, timestamp: u64, sequence: u64, summary: Summary) {
+        let record = Record {
+            timestamp,
+            sequence,
+            summary,
+        };
+        if self.lossless {
+            if self.tx.send(record).await.is_err() {
+                warn!("History writer stopped, book {} dropped.", sequence);
+            }
+        } else if self.tx.try_send(record).is_err() {
+            warn!(
+                "History writer is falling behind, book {} dropped.",
+                sequence
+            );
+        }
+    }
+
+    // Wait for the queued books to be written.
+    pub async fn close(self) {
+        drop(self.tx);
+        let _ = self.writer.await;
+    }
+}
+
+struct HistoryWriter {
+    dir: PathBuf,
+    symbol: String,
+    // minimum time between two written books, in microseconds.
+    interval: u64,
+    file: Option<(PathBuf, BufWriter<File>)>,
+    // timestamp and summary of the last written book.
+    last: Option<(u64, Summary)>,
+}
+
+impl HistoryWriter {
+    fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        if let Some((timestamp, summary)) = &self.last {
+            // books that are unchanged, or within the interval of the last written one, are
+            // skipped.
+            if *summary == record.summary || record.timestamp < timestamp + self.interval {
+                return Ok(());
+            }
+        }
+        let path = self.partition(record.timestamp);
+        if self.file.as_ref().map(|(p, _)| p) != Some(&path) {
+            self.flush()?;
+            fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let is_new = file.metadata()?.len() == 0;
+            let mut file = BufWriter::new(file);
+            if is_new {
+                writeln!(file, "{}", HEADER)?;
+            }
+            info!("Writing book history to {}.", path.display());
+            self.file = Some((path, file));
+        }
+        let Some((_, file)) = &mut self.file else {
+            return Ok(());
+        };
+        let sides = [("bid", &record.summary.bids), ("ask", &record.summary.asks)];
+        for (side, levels) in sides {
+            for (index, level) in levels.iter().enumerate() {
+                writeln!(
+                    file,
+                    "{},{},{},{},{},{},{},{}",
+                    record.timestamp,
+                    record.sequence,
+                    self.symbol,
+                    side,
+                    index,
+                    level.exchange,
+                    level.price,
+                    level.amount
+                )?;
+            }
+        }
+        self.last = Some((record.timestamp, record.summary.clone()));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some((_, file)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // File of the hour the timestamp falls in, e.g. date=2022-08-27/ltcbtc-07.csv.
+    fn partition(&self, timestamp: u64) -> PathBuf {
+        let seconds = timestamp / 1_000_000;
+        let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+        self.dir
+            .join(format!("date={:04}-{:02}-{:02}", year, month, day))
+            .join(format!("{}-{:02}.csv", self.symbol, seconds % 86400 / 3600))
+    }
+}
+
+// Date of the given number of days since 1970-01-01, in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{civil_from_days, HistoryWriter, Record};
+    use crate::fixtures::{summary, TempDir};
+
+    #[test]
+    fn books_are_written_to_hourly_partitions() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19231), (2022, 8, 27));
+
+        let dir = TempDir::new("history");
+        let mut writer = HistoryWriter {
+            dir: dir.path().to_path_buf(),
+            symbol: "ltcbtc".into(),
+            interval: 1_000_000,
+            file: None,
+            last: None,
+        };
+        // 2022-08-27 07:29:27 UTC.
+        let start = 1_661_585_367_000_000;
+        let records = [
+            (start, 1, summary(0.0009)),
+            // within the interval of the written book, then unchanged.
+            (start + 500_000, 2, summary(0.0008)),
+            (start + 2_000_000, 3, summary(0.0009)),
+            (start + 3_000_000, 4, summary(0.0007)),
+            // the next hour.
+            (start + 3_600_000_000, 5, summary(0.0006)),
+        ];
+        for (timestamp, sequence, summary) in records {
+            let record = Record {
+                timestamp,
+                sequence,
+                summary,
+            };
+            writer.write(&record).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let partition = dir.path().join("date=2022-08-27");
+        let first = std::fs::read_to_string(partition.join("ltcbtc-07.csv")).unwrap();
+        let lines: Vec<&str> = first.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], super::HEADER);
+        assert_eq!(
+            lines[1],
+            "1661585367000000,1,ltcbtc,bid,0,binance,0.0009,1.5"
+        );
+        assert!(lines[3].starts_with("1661585370000000,4,ltcbtc,bid"));
+        let second = std::fs::read_to_string(partition.join("ltcbtc-08.csv")).unwrap();
+        assert_eq!(second.lines().count(), 3);
+    }
+}
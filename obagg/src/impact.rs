@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    definitions::Orderbook,
+    error::ObaggError,
+    implied,
+    orderbook::{ExchangeFill, ImpactRequest, ImpactResponse, Level, Side},
+};
+
+pub fn validate(req: &ImpactRequest) -> Result<(), ObaggError> {
+    let valid = |size: f64| size.is_finite() && size >= 0.0;
+    if !valid(req.quantity) || !valid(req.notional) {
+        return Err(ObaggError(
+            "Invalid order size : must be a positive number".into(),
+        ));
+    }
+    if (req.quantity > 0.0) == (req.notional > 0.0) {
+        return Err(ObaggError(
+            "Invalid order size : exactly one of quantity and notional must be set".into(),
+        ));
+    }
+    Ok(())
+}
+
+// Walk the aggregated book for an order of the requested size, best prices first, and report the
+// price it would fill at and how the fill splits across exchanges. Implied levels restate the
+// liquidity of their legs, they are only walked when the request lists them.
+pub fn market_impact(book: &Orderbook, req: &ImpactRequest) -> Result<ImpactResponse, ObaggError> {
+    let direct = |level: &&Level| !implied::is_implied(&level.exchange);
+    let (Some(best_bid), Some(best_ask)) = (
+        book.bids.values().rev().find(direct),
+        book.asks.values().find(direct),
+    ) else {
+        return Err(ObaggError(
+            "Aggregated orderbook is not available yet".into(),
+        ));
+    };
+    let mid = (best_bid.price + best_ask.price) / 2.0;
+
+    let levels: Box<dyn Iterator<Item = _>> = match req.side() {
+        Side::Buy => Box::new(book.asks.values()),
+        Side::Sell => Box::new(book.bids.values().rev()),
+    };
+    let mut fills: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+    let (mut quantity, mut notional, mut worst_price) = (0.0, 0.0, 0.0);
+    let walked = |level: &&Level| {
+        if req.exchanges.is_empty() {
+            direct(level)
+        } else {
+            req.exchanges.contains(&level.exchange)
+        }
+    };
+    for level in levels.filter(walked) {
+        let wanted = if req.quantity > 0.0 {
+            req.quantity - quantity
+        } else {
+            (req.notional - notional) / level.price
+        };
+        let filled = level.amount.min(wanted);
+        if filled <= 0.0 {
+            break;
+        }
+        let fill = fills.entry(level.exchange.as_str()).or_default();
+        fill.0 += filled;
+        fill.1 += filled * level.price;
+        quantity += filled;
+        notional += filled * level.price;
+        worst_price = level.price;
+        // the order is complete, the next levels are left untouched.
+        if filled >= wanted {
+            break;
+        }
+    }
+    if quantity <= 0.0 {
+        return Err(ObaggError(
+            "No liquidity available for the requested exchanges".into(),
+        ));
+    }
+
+    let vwap = notional / quantity;
+    let slippage = match req.side() {
+        Side::Buy => (vwap - mid) / mid,
+        Side::Sell => (mid - vwap) / mid,
+    };
+    Ok(ImpactResponse {
+        vwap,
+        worst_price,
+        mid,
+        slippage,
+        filled_quantity: quantity,
+        filled_notional: notional,
+        fills: fills
+            .into_iter()
+            .map(|(exchange, (quantity, notional))| ExchangeFill {
+                exchange: exchange.into(),
+                quantity,
+                notional,
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::{market_impact, validate};
+    use crate::{
+        definitions::Orderbook,
+        orderbook::{ImpactRequest, Level, Side},
+    };
+
+    fn book() -> Orderbook {
+        let level = |exchange: &str, price: i64, amount: f64| {
+            let level = Level {
+                exchange: exchange.into(),
+                price: price as f64,
+                amount,
+                ..Default::default()
+            };
+            (Decimal::from(price), level)
+        };
+        let mut book = Orderbook::new();
+        book.bids = [level("binance", 99, 1.0), level("bitstamp", 98, 2.0)].into();
+        book.asks = [
+            level("bitstamp", 101, 1.0),
+            level("binance", 102, 2.0),
+            level("bitstamp", 104, 5.0),
+        ]
+        .into();
+        book
+    }
+
+    #[test]
+    fn buy_walks_asks_across_exchanges() {
+        let req = ImpactRequest {
+            side: Side::Buy as i32,
+            quantity: 2.0,
+            ..Default::default()
+        };
+        validate(&req).unwrap();
+        let impact = market_impact(&book(), &req).unwrap();
+        assert_eq!(impact.vwap, 101.5);
+        assert_eq!(impact.worst_price, 102.0);
+        assert_eq!(impact.mid, 100.0);
+        assert_eq!(impact.slippage, 0.015);
+        assert_eq!(impact.filled_quantity, 2.0);
+        assert_eq!(impact.fills.len(), 2);
+        assert_eq!(impact.fills[0].exchange, "binance");
+        assert_eq!(impact.fills[0].quantity, 1.0);
+        assert_eq!(impact.fills[1].notional, 101.0);
+    }
+
+    #[test]
+    fn order_using_up_a_level_stops_there() {
+        let req = ImpactRequest {
+            side: Side::Buy as i32,
+            quantity: 1.0,
+            ..Default::default()
+        };
+        let impact = market_impact(&book(), &req).unwrap();
+        assert_eq!(impact.vwap, 101.0);
+        assert_eq!(impact.worst_price, 101.0);
+        assert_eq!(impact.slippage, 0.01);
+        assert_eq!(impact.fills.len(), 1);
+        assert_eq!(impact.fills[0].exchange, "bitstamp");
+        assert_eq!(impact.fills[0].quantity, 1.0);
+    }
+
+    #[test]
+    fn sell_notional_with_exchange_filter() {
+        let req = ImpactRequest {
+            side: Side::Sell as i32,
+            notional: 500.0,
+            exchanges: vec!["bitstamp".into()],
+            ..Default::default()
+        };
+        // the filtered book only holds 196 of the requested notional.
+        let impact = market_impact(&book(), &req).unwrap();
+        assert_eq!(impact.vwap, 98.0);
+        assert_eq!(impact.filled_quantity, 2.0);
+        assert_eq!(impact.filled_notional, 196.0);
+        assert_eq!(impact.fills.len(), 1);
+
+        assert!(validate(&ImpactRequest::default()).is_err());
+        let both = ImpactRequest {
+            quantity: 1.0,
+            notional: 1.0,
+            ..Default::default()
+        };
+        assert!(validate(&both).is_err());
+    }
+
+    #[test]
+    fn implied_levels_are_walked_only_when_listed() {
+        let mut book = book();
+        let implied = Level {
+            exchange: "binance:implied".into(),
+            price: 100.0,
+            amount: 5.0,
+            ..Default::default()
+        };
+        book.asks.insert(Decimal::from(100), implied);
+        let mut req = ImpactRequest {
+            side: Side::Buy as i32,
+            quantity: 2.0,
+            ..Default::default()
+        };
+        // the implied ask restates the binance legs, the direct levels are walked as before.
+        let impact = market_impact(&book, &req).unwrap();
+        assert_eq!((impact.vwap, impact.mid), (101.5, 100.0));
+        assert!(impact.fills.iter().all(|f| f.exchange != "binance:implied"));
+
+        req.exchanges = vec!["binance:implied".into()];
+        let impact = market_impact(&book, &req).unwrap();
+        assert_eq!(impact.vwap, 100.0);
+        assert_eq!(impact.fills[0].exchange, "binance:implied");
+    }
+}
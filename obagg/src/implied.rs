@@ -0,0 +1,179 @@
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal,
+};
+
+use crate::{config::ImpliedMarket, definitions::Orderbook, normalize::Markets, orderbook::Level};
+
+// Suffix of the exchange label of the implied levels, e.g. binance:implied.
+const IMPLIED: &str = ":implied";
+
+// Whether the levels of the exchange label are implied from the legs of another market, they
+// restate liquidity already quoted on the legs.
+pub fn is_implied(exchange: &str) -> bool {
+    exchange.ends_with(IMPLIED)
+}
+
+// Exchange label of the levels implied for the market.
+pub fn label(market: &ImpliedMarket) -> String {
+    format!("{}{IMPLIED}", market.exchange)
+}
+
+// Derive the book of the ticker from its two legs on the same exchange, e.g. ltcbtc from ltcusdt
+// and btcusdt. Selling into an implied bid sells the base leg bids and buys the quote leg asks,
+// buying from an implied ask sells the quote leg bids and buys the base leg asks. None until both
+// legs are known.
+pub fn implied(market: &ImpliedMarket, markets: &Markets, depth: usize) -> Option<Orderbook> {
+    let leg = |ticker: &str| {
+        markets
+            .iter()
+            .find(|((exchange, t), _)| *exchange == market.exchange && t == ticker)
+            .map(|(_, book)| book)
+    };
+    let base = leg(&market.base)?;
+    let quote = leg(&market.quote)?;
+    let exchange = label(market);
+    let mut orderbook = Orderbook::new();
+    orderbook.sequence = base.sequence.max(quote.sequence);
+    orderbook.bids = walk(
+        levels(base.bids.iter().rev()),
+        levels(quote.asks.iter()),
+        depth,
+    )
+    .into_iter()
+    .map(|(price, amount)| (price, level(&exchange, price, amount)))
+    .collect();
+    orderbook.asks = walk(
+        levels(base.asks.iter()),
+        levels(quote.bids.iter().rev()),
+        depth,
+    )
+    .into_iter()
+    .map(|(price, amount)| (price, level(&exchange, price, amount)))
+    .collect();
+    Some(orderbook)
+}
+
+fn levels<'a>(
+    levels: impl Iterator<Item = (&'a Decimal, &'a Level)> + 'a,
+) -> impl Iterator<Item = (Decimal, Decimal)> + 'a {
+    levels
+        .filter(|(price, _)| !price.is_zero())
+        .filter_map(|(price, level)| Some((*price, Decimal::from_f64(level.amount)?)))
+}
+
+fn level(exchange: &str, price: Decimal, amount: Decimal) -> Level {
+    Level {
+        exchange: exchange.into(),
+        price: price.to_f64().unwrap_or(0.0),
+        amount: amount.to_f64().unwrap_or(0.0),
+        ..Default::default()
+    }
+}
+
+// Walk both legs by notional in their common currency, best levels first. Each step fills the
+// smaller remaining notional of the current level of either leg, so the implied amount is never
+// more than both legs can execute. Returns at most depth (price, base amount) levels.
+fn walk(
+    mut base: impl Iterator<Item = (Decimal, Decimal)>,
+    mut quote: impl Iterator<Item = (Decimal, Decimal)>,
+    depth: usize,
+) -> Vec<(Decimal, Decimal)> {
+    let notional = |(price, amount): (Decimal, Decimal)| (price, price * amount);
+    let mut out: Vec<(Decimal, Decimal)> = vec![];
+    let (mut b, mut q) = match (base.next().map(notional), quote.next().map(notional)) {
+        (Some(b), Some(q)) => (b, q),
+        _ => return out,
+    };
+    while out.len() < depth {
+        let filled = b.1.min(q.1);
+        let price = b.0 / q.0;
+        match out.last_mut() {
+            Some(last) if last.0 == price => last.1 += filled / b.0,
+            _ => out.push((price, filled / b.0)),
+        }
+        b.1 -= filled;
+        q.1 -= filled;
+        if b.1.is_zero() {
+            match base.next() {
+                Some(next) => b = notional(next),
+                None => break,
+            }
+        }
+        if q.1.is_zero() {
+            match quote.next() {
+                Some(next) => q = notional(next),
+                None => break,
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::implied;
+    use crate::{
+        config::ImpliedMarket, definitions::Orderbook, normalize::Markets, orderbook::Level,
+    };
+
+    fn book(bids: &[(i64, f64)], asks: &[(i64, f64)]) -> Orderbook {
+        let level = |&(price, amount): &(i64, f64)| {
+            let level = Level {
+                exchange: "binance".into(),
+                price: price as f64,
+                amount,
+                ..Default::default()
+            };
+            (Decimal::from(price), level)
+        };
+        let mut book = Orderbook::new();
+        book.bids = bids.iter().map(level).collect();
+        book.asks = asks.iter().map(level).collect();
+        book
+    }
+
+    #[test]
+    fn implied_book_walks_both_legs() {
+        let market = ImpliedMarket {
+            exchange: "binance".into(),
+            base: "ltcusdt".into(),
+            quote: "btcusdt".into(),
+        };
+        let mut markets = Markets::new();
+        markets.insert(
+            ("binance", "ltcusdt".into()),
+            book(&[(100, 10.0), (99, 10.0)], &[(101, 5.0)]),
+        );
+        assert!(implied(&market, &markets, 10).is_none());
+        markets.insert(
+            ("binance", "btcusdt".into()),
+            book(&[(20000, 1.0)], &[(20000, 0.02), (25000, 1.0)]),
+        );
+
+        let orderbook = implied(&market, &markets, 10).unwrap();
+        // 400 usdt of the best btcusdt ask fills 4 ltc at 100 / 20000, the remaining 6 ltc and
+        // the next ltcusdt level are filled at the next btcusdt ask.
+        let bids: Vec<(f64, f64)> = orderbook
+            .bids
+            .values()
+            .rev()
+            .map(|l| (l.price, l.amount))
+            .collect();
+        assert_eq!(bids, vec![(0.005, 4.0), (0.004, 6.0), (0.00396, 10.0)]);
+        assert_eq!(
+            orderbook.bids.values().next().unwrap().exchange,
+            "binance:implied"
+        );
+
+        // buying 5 ltc costs 505 usdt, which sells 0.02525 btc at 20000.
+        let asks: Vec<(f64, f64)> = orderbook
+            .asks
+            .values()
+            .map(|l| (l.price, l.amount))
+            .collect();
+        assert_eq!(asks, vec![(0.00505, 5.0)]);
+    }
+}
@@ -1,17 +1,46 @@
+pub use check::{check_config, Problem};
 pub use client::client;
+pub use replay::replay;
 pub use server::server;
-mod orderbook {
+pub mod orderbook {
     tonic::include_proto!("orderbook");
 }
+mod capture {
+    tonic::include_proto!("capture");
+}
 
 mod aggregator;
+mod analytics;
+mod arbitrage;
+#[doc(hidden)]
+pub mod bench;
 mod binance;
 mod bitstamp;
+mod candles;
+mod check;
 mod client;
 pub mod config;
 mod definitions;
 mod error;
+mod feed;
+#[cfg(test)]
+mod fixtures;
 mod grpc;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod harness;
+mod history;
+mod impact;
+mod implied;
+mod metrics;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod mock;
+mod normalize;
+mod recorder;
+mod replay;
+mod routing;
 mod serde;
 mod server;
+mod store;
+mod subscriber;
 mod utils;
+mod view;
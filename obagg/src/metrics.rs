@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::orderbook::ServerMetrics;
+
+// Counters shared between the components of the server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // aggregated books that were not published because the top-N levels did not change.
+    pub suppressed_publishes: AtomicU64,
+    // summaries conflated because a client queue was full.
+    pub conflated_publishes: AtomicU64,
+    // clients disconnected by the slow client policy.
+    pub evicted_clients: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Current value of the counters, as served by the GetMetrics RPC.
+    pub fn snapshot(&self) -> ServerMetrics {
+        ServerMetrics {
+            suppressed_publishes: self.suppressed_publishes.load(Ordering::Relaxed),
+            conflated_publishes: self.conflated_publishes.load(Ordering::Relaxed),
+            evicted_clients: self.evicted_clients.load(Ordering::Relaxed),
+        }
+    }
+}
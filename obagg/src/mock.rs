@@ -0,0 +1,305 @@
+// Local exchange servers for the tests. A mock exchange serves a websocket endpoint and a REST
+// endpoint speaking the binance and bitstamp protocols, so the exchange configuration can be
+// pointed at it and the consumers exercised offline. Each websocket connection plays the next
+// scripted scenario, the REST endpoint serves the depth snapshots in order.
+use futures::{SinkExt, StreamExt};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Notify},
+    time::{sleep, timeout, Duration},
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        Message,
+    },
+};
+use tonic::Status;
+
+use crate::{
+    config,
+    definitions::{MarketData, Orderbooks},
+};
+
+pub enum Step {
+    // send a text frame, malformed or not.
+    Send(String),
+    // wait for the test to notify, e.g. once a client subscribed.
+    Wait(Arc<Notify>),
+    // ping the client, which answers with a pong.
+    Ping,
+    // close the websocket with a close frame.
+    Close,
+    // drop the connection without closing the websocket.
+    Drop,
+}
+
+#[derive(Default)]
+struct State {
+    // scenarios of the next websocket connections, a connection with none stays open and idle.
+    scripts: Mutex<VecDeque<Vec<Step>>>,
+    // snapshots of the REST endpoint, the last one is served again once the others were.
+    snapshots: Mutex<VecDeque<String>>,
+    connections: AtomicUsize,
+    pings: AtomicUsize,
+    pongs: AtomicUsize,
+    // websocket paths, text frames received from the clients and REST requests, in order.
+    requests: Mutex<Vec<String>>,
+}
+
+pub struct MockExchange {
+    pub websocket: String,
+    pub api: String,
+    state: Arc<State>,
+}
+
+impl MockExchange {
+    pub async fn start(scripts: Vec<Vec<Step>>, snapshots: Vec<String>) -> Self {
+        let state = Arc::new(State {
+            scripts: Mutex::new(scripts.into()),
+            snapshots: Mutex::new(snapshots.into()),
+            ..Default::default()
+        });
+        let websocket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let exchange = MockExchange {
+            websocket: format!("ws://{}", websocket.local_addr().unwrap()),
+            api: format!("http://{}", api.local_addr().unwrap()),
+            state: state.clone(),
+        };
+        let ws_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = websocket.accept().await {
+                tokio::spawn(serve_websocket(stream, ws_state.clone()));
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = api.accept().await {
+                tokio::spawn(serve_rest(stream, state.clone()));
+            }
+        });
+        exchange
+    }
+
+    // Configuration of an exchange consuming from the mock.
+    pub fn exchange(&self) -> config::Exchange {
+        config::Exchange {
+            api: self.api.clone(),
+            enable: true,
+            websocket: self.websocket.clone(),
+            ping_period: 1,
+            period: None,
+            reconnect_period: None,
+            connections: None,
+            taker_fee: None,
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn pings(&self) -> usize {
+        self.state.pings.load(Ordering::SeqCst)
+    }
+
+    pub fn pongs(&self) -> usize {
+        self.state.pongs.load(Ordering::SeqCst)
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    // Wait for the clients to act on the mock, returns whether the condition holds within 5
+    // seconds.
+    pub async fn until(&self, condition: impl Fn(&Self) -> bool) -> bool {
+        let waited = timeout(Duration::from_secs(5), async {
+            while !condition(self) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        });
+        waited.await.is_ok()
+    }
+}
+
+async fn serve_websocket(stream: TcpStream, state: Arc<State>) {
+    // the error response type is imposed by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, resp: Response| {
+        let path = req.uri().to_string();
+        state.requests.lock().unwrap().push(path);
+        Ok(resp)
+    };
+    let Ok(ws_stream) = accept_hdr_async(stream, callback).await else {
+        return;
+    };
+    state.connections.fetch_add(1, Ordering::SeqCst);
+    let script = state
+        .scripts
+        .lock()
+        .unwrap()
+        .pop_front()
+        .unwrap_or_default();
+    let (mut write, mut read) = ws_stream.split();
+    let reader_state = state.clone();
+    let mut reader = tokio::spawn(async move {
+        while let Some(Ok(message)) = read.next().await {
+            match message {
+                Message::Text(text) => reader_state.requests.lock().unwrap().push(text),
+                Message::Ping(_) => {
+                    reader_state.pings.fetch_add(1, Ordering::SeqCst);
+                }
+                Message::Pong(_) => {
+                    reader_state.pongs.fetch_add(1, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+        }
+    });
+    for step in script {
+        let sent = match step {
+            Step::Send(text) => write.send(Message::Text(text)).await,
+            Step::Wait(notify) => {
+                notify.notified().await;
+                Ok(())
+            }
+            Step::Ping => write.send(Message::Ping(vec![1])).await,
+            Step::Close => {
+                let _ = write.send(Message::Close(None)).await;
+                reader.abort();
+                return;
+            }
+            Step::Drop => {
+                reader.abort();
+                return;
+            }
+        };
+        if sent.is_err() {
+            reader.abort();
+            return;
+        }
+    }
+    // answer the pings of the client until it disconnects.
+    let _ = (&mut reader).await;
+}
+
+async fn serve_rest(mut stream: TcpStream, state: Arc<State>) {
+    let mut buf = vec![];
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&buf);
+    // request line, e.g. GET /api/v3/depth?symbol=LTCBTC&limit=100 HTTP/1.1
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    state.requests.lock().unwrap().push(target.into());
+    let body = {
+        let mut snapshots = state.snapshots.lock().unwrap();
+        match snapshots.len() {
+            0 => String::new(),
+            1 => snapshots[0].clone(),
+            _ => snapshots.pop_front().unwrap_or_default(),
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+// Configuration of a server consuming ltcbtc from the mock exchanges, an exchange without a mock
+// is disabled.
+pub fn conf(
+    depth: usize,
+    binance: Option<&MockExchange>,
+    bitstamp: Option<&MockExchange>,
+) -> config::Server {
+    let mut disabled = config::Server::builder().build().exchanges;
+    disabled.binance.enable = false;
+    disabled.bitstamp.enable = false;
+    config::Server::builder()
+        .bind_address(([127, 0, 0, 1], 0).into())
+        .depth(depth)
+        .binance(binance.map_or(disabled.binance, |m| m.exchange()))
+        .bitstamp(bitstamp.map_or(disabled.bitstamp, |m| m.exchange()))
+        .build()
+}
+
+fn levels(levels: &[(&str, &str)]) -> String {
+    serde_json::to_string(levels).unwrap()
+}
+
+// Message of the binance partial depth stream, also the shape of the REST depth snapshot.
+pub fn binance_depth(last_update_id: u64, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> String {
+    format!(
+        r#"{{"lastUpdateId":{},"bids":{},"asks":{}}}"#,
+        last_update_id,
+        levels(bids),
+        levels(asks)
+    )
+}
+
+// Message of the binance diff depth stream, holding the updates first to last.
+pub fn binance_update(
+    first: u64,
+    last: u64,
+    bids: &[(&str, &str)],
+    asks: &[(&str, &str)],
+) -> String {
+    format!(
+        r#"{{"e":"depthUpdate","E":1661585367425,"s":"LTCBTC","U":{},"u":{},"b":{},"a":{}}}"#,
+        first,
+        last,
+        levels(bids),
+        levels(asks)
+    )
+}
+
+pub fn bitstamp_subscribed(channel: &str) -> String {
+    format!(r#"{{"event":"bts:subscription_succeeded","channel":"{channel}","data":{{}}}}"#)
+}
+
+pub fn bitstamp_book(microtimestamp: u64, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> String {
+    format!(
+        r#"{{"data":{{"timestamp":"{}","microtimestamp":"{}","bids":{},"asks":{}}},"channel":"order_book_ltcbtc","event":"data"}}"#,
+        microtimestamp / 1_000_000,
+        microtimestamp,
+        levels(bids),
+        levels(asks)
+    )
+}
+
+pub fn bitstamp_reconnect() -> String {
+    r#"{"event":"bts:request_reconnect","channel":"","data":""}"#.into()
+}
+
+// Sequences of the next n books sent to the aggregator, fewer when they do not arrive in time.
+pub async fn sequences(rx: &mut mpsc::Receiver<Result<MarketData, Status>>, n: usize) -> Vec<u64> {
+    let mut out = vec![];
+    for _ in 0..n {
+        match timeout(Duration::from_secs(5), rx.recv()).await {
+            Ok(Some(Ok(MarketData::Book(Orderbooks::Binance(ob) | Orderbooks::Bitstamp(ob))))) => {
+                out.push(ob.sequence)
+            }
+            _ => break,
+        }
+    }
+    out
+}
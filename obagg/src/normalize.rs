@@ -0,0 +1,130 @@
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::collections::HashMap;
+
+use crate::{config::QuoteMarket, definitions::Orderbook, orderbook::Level};
+
+// Orderbooks of the auxiliary markets, keyed by exchange and ticker.
+pub type Markets = HashMap<(&'static str, String), Orderbook>;
+
+// Mid price of the rate market aggregated across its exchanges, converting one unit of the quote
+// currency into the ticker quote. None until both sides of the rate market are known.
+pub fn rate(quote: &QuoteMarket, markets: &Markets) -> Option<Decimal> {
+    let books = || {
+        markets
+            .iter()
+            .filter(|((exchange, ticker), _)| {
+                *ticker == quote.rate.ticker && quote.rate.exchanges.iter().any(|e| e == exchange)
+            })
+            .map(|(_, book)| book)
+    };
+    let best_bid = books().filter_map(|b| b.bids.keys().next_back()).max()?;
+    let best_ask = books().filter_map(|b| b.asks.keys().next()).min()?;
+    let mid = (best_bid + best_ask) / Decimal::TWO;
+    if quote.rate.invert.unwrap_or(false) {
+        (!mid.is_zero()).then(|| Decimal::ONE / mid)
+    } else {
+        Some(mid)
+    }
+}
+
+// Convert the books of a quote market into the ticker quote, one book per exchange labelled
+// `<exchange>:<quote>`, e.g. binance:usdt.
+pub fn convert(quote: &QuoteMarket, markets: &Markets, rate: Decimal) -> Vec<(String, Orderbook)> {
+    markets
+        .iter()
+        .filter(|((exchange, ticker), _)| {
+            *ticker == quote.ticker && quote.exchanges.iter().any(|e| e == exchange)
+        })
+        .map(|((exchange, _), book)| {
+            let mut converted = Orderbook::new();
+            converted.sequence = book.sequence;
+            let level = |(price, level): (&Decimal, &Level)| {
+                let price = price * rate;
+                let level = Level {
+                    price: price.to_f64().unwrap_or(0.0),
+                    quote: quote.quote.clone(),
+                    rate: rate.to_f64().unwrap_or(0.0),
+                    ..level.clone()
+                };
+                (price, level)
+            };
+            converted.bids = book.bids.iter().map(level).collect();
+            converted.asks = book.asks.iter().map(level).collect();
+            (format!("{}:{}", exchange, quote.quote), converted)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::{convert, rate, Markets};
+    use crate::{
+        config::{QuoteMarket, RateMarket},
+        definitions::Orderbook,
+        orderbook::Level,
+    };
+
+    fn book(exchange: &str, bid: Decimal, ask: Decimal) -> Orderbook {
+        let level = |price: Decimal| Level {
+            exchange: exchange.into(),
+            price: price.to_string().parse().unwrap(),
+            amount: 1.0,
+            ..Default::default()
+        };
+        let mut book = Orderbook::new();
+        book.bids.insert(bid, level(bid));
+        book.asks.insert(ask, level(ask));
+        book
+    }
+
+    fn quote(invert: bool) -> QuoteMarket {
+        QuoteMarket {
+            ticker: "btcusdt".into(),
+            quote: "usdt".into(),
+            exchanges: vec!["binance".into()],
+            rate: RateMarket {
+                ticker: "usdtusd".into(),
+                exchanges: vec!["binance".into(), "bitstamp".into()],
+                invert: Some(invert),
+            },
+        }
+    }
+
+    #[test]
+    fn quote_market_is_converted_at_rate_mid() {
+        let mut markets = Markets::new();
+        assert_eq!(rate(&quote(false), &markets), None);
+
+        // the rate is the mid of the best bid and ask across the rate exchanges.
+        markets.insert(
+            ("binance", "usdtusd".into()),
+            book("binance", Decimal::new(9990, 4), Decimal::new(10010, 4)),
+        );
+        markets.insert(
+            ("bitstamp", "usdtusd".into()),
+            book("bitstamp", Decimal::new(9996, 4), Decimal::new(10020, 4)),
+        );
+        markets.insert(
+            ("binance", "btcusdt".into()),
+            book("binance", Decimal::new(20000, 0), Decimal::new(20010, 0)),
+        );
+        let r = rate(&quote(false), &markets).unwrap();
+        assert_eq!(r, Decimal::new(10003, 4));
+        assert_eq!(
+            rate(&quote(true), &markets).unwrap(),
+            Decimal::ONE / Decimal::new(10003, 4)
+        );
+
+        let converted = convert(&quote(false), &markets, r);
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].0, "binance:usdt");
+        let (price, level) = converted[0].1.bids.iter().next().unwrap();
+        assert_eq!(*price, Decimal::new(20006, 0));
+        assert_eq!(level.price, 20006.0);
+        assert_eq!(level.exchange, "binance");
+        assert_eq!(level.quote, "usdt");
+        assert_eq!(level.rate, 1.0003);
+    }
+}
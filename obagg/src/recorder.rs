@@ -0,0 +1,297 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{error, info, warn};
+use prost::Message;
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_tungstenite::tungstenite;
+
+use crate::{
+    capture::{frame::Kind, Frame},
+    config, utils,
+};
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+// Frames waiting to be written, beyond which new frames are dropped rather than slowing down the
+// consumers.
+const QUEUE: usize = 65536;
+
+// Handle to the capture writer, shared by every feed.
+#[derive(Clone)]
+pub struct Recorder {
+    tx: mpsc::Sender<Frame>,
+}
+
+impl Recorder {
+    // Start the writer of the capture files in the configured directory.
+    pub fn spawn(conf: &config::Recording) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self::spawn_writer(conf)?.0)
+    }
+
+    // Start the writer, along with its task that completes once the recorder and all of its taps
+    // were dropped and the queued frames written.
+    pub fn spawn_writer(
+        conf: &config::Recording,
+    ) -> Result<(Self, JoinHandle<()>), Box<dyn Error + Send + Sync>> {
+        fs::create_dir_all(&conf.path)?;
+        let mut writer = CaptureWriter {
+            dir: PathBuf::from(&conf.path),
+            max_file_size: conf.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            file: None,
+            written: 0,
+        };
+        let (tx, mut rx) = mpsc::channel::<Frame>(QUEUE);
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(frame) = rx.blocking_recv() {
+                let mut result = writer.write(&frame);
+                // write whatever else is queued before flushing the compressed stream.
+                while let (Ok(()), Ok(frame)) = (&result, rx.try_recv()) {
+                    result = writer.write(&frame);
+                }
+                if let Err(e) = result.and_then(|_| writer.flush()) {
+                    error!("Error writing capture file, recording stopped : {}", e);
+                    return;
+                }
+            }
+            if let Err(e) = writer.finish() {
+                error!("Error closing capture file : {}", e);
+            }
+        });
+        Ok((Recorder { tx }, writer))
+    }
+
+    // Bind the recorder to the feed of a market channel.
+    pub fn tap(&self, exchange: &'static str, ticker: &str, channel: &'static str) -> Tap {
+        Tap {
+            recorder: self.clone(),
+            exchange,
+            ticker: ticker.into(),
+            channel,
+        }
+    }
+}
+
+// Recorder bound to a feed, given to its websocket consumers.
+#[derive(Clone)]
+pub struct Tap {
+    recorder: Recorder,
+    exchange: &'static str,
+    ticker: Arc<str>,
+    channel: &'static str,
+}
+
+impl Tap {
+    pub fn record(&self, connection: usize, kind: Kind, payload: &str) {
+        self.record_frame(connection, kind, payload.into(), vec![]);
+    }
+
+    // Record a websocket message of any type, as received before it is handled.
+    pub fn record_message(&self, connection: usize, message: &tungstenite::Message) {
+        let (kind, data) = match message {
+            tungstenite::Message::Text(text) => return self.record(connection, Kind::Text, text),
+            tungstenite::Message::Binary(data) => (Kind::Binary, data.clone()),
+            tungstenite::Message::Ping(data) => (Kind::Ping, data.clone()),
+            tungstenite::Message::Pong(data) => (Kind::Pong, data.clone()),
+            tungstenite::Message::Close(frame) => {
+                let data = frame.iter().flat_map(|frame| {
+                    let code = u16::from(frame.code).to_be_bytes();
+                    code.into_iter().chain(frame.reason.bytes())
+                });
+                (Kind::Close, data.collect())
+            }
+            tungstenite::Message::Frame(frame) => (Kind::Binary, frame.payload().clone()),
+        };
+        self.record_frame(connection, kind, String::new(), data);
+    }
+
+    fn record_frame(&self, connection: usize, kind: Kind, payload: String, data: Vec<u8>) {
+        let frame = Frame {
+            received: utils::now_micros(),
+            exchange: self.exchange.into(),
+            ticker: self.ticker.to_string(),
+            channel: self.channel.into(),
+            connection: connection as u64,
+            kind: kind as i32,
+            payload,
+            data,
+        };
+        if self.recorder.tx.try_send(frame).is_err() {
+            warn!(
+                "Capture writer is falling behind, {} frame dropped.",
+                self.exchange
+            );
+        }
+    }
+}
+
+// Writes length-delimited frames to gzip compressed files, starting a new file once the current
+// one holds max_file_size bytes of frames.
+struct CaptureWriter {
+    dir: PathBuf,
+    max_file_size: u64,
+    file: Option<GzEncoder<BufWriter<File>>>,
+    written: u64,
+}
+
+impl CaptureWriter {
+    fn write(&mut self, frame: &Frame) -> std::io::Result<()> {
+        if self.written >= self.max_file_size {
+            self.finish()?;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = self.create(frame.received)?;
+                self.written = 0;
+                self.file
+                    .insert(GzEncoder::new(BufWriter::new(file), Compression::default()))
+            }
+        };
+        let buf = frame.encode_length_delimited_to_vec();
+        file.write_all(&buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    // Create the file of the captures started at the given time. An existing file is never
+    // overwritten, the files started within the same microsecond are numbered.
+    fn create(&self, started: u64) -> std::io::Result<File> {
+        let mut n = 0;
+        loop {
+            let name = match n {
+                0 => format!("obagg-{}.cap.gz", started),
+                n => format!("obagg-{}-{}.cap.gz", started, n),
+            };
+            let path = self.dir.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+                result => {
+                    info!("Recording exchange frames to {}.", path.display());
+                    return result;
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.finish()?.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Read the frames of a capture file, in the order they were received. The file being written by
+// a running recorder is not complete yet, it is read up to its last flushed frame.
+pub fn read_file(path: &Path) -> Result<Vec<Frame>, Box<dyn Error + Send + Sync>> {
+    let mut buf = vec![];
+    let complete = match GzDecoder::new(BufReader::new(File::open(path)?)).read_to_end(&mut buf) {
+        Ok(_) => true,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e.into()),
+    };
+    let mut frames = vec![];
+    let mut buf = buf.as_slice();
+    while !buf.is_empty() {
+        match Frame::decode_length_delimited(&mut buf) {
+            Ok(frame) => frames.push(frame),
+            Err(_) if !complete => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(frames)
+}
+
+// The capture files of a directory, oldest first.
+pub fn capture_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    let mut files: Vec<((u64, u64), PathBuf)> = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // obagg-<started>.cap.gz, or obagg-<started>-<n>.cap.gz for the n-th file started within
+        // the same microsecond.
+        let started = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("obagg-")?.strip_suffix(".cap.gz"))
+            .and_then(|n| match n.split_once('-') {
+                Some((started, n)) => Some((started.parse().ok()?, n.parse().ok()?)),
+                None => Some((n.parse().ok()?, 0)),
+            });
+        if let Some(started) = started {
+            files.push((started, path));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    };
+
+    use super::{capture_files, read_file, Recorder};
+    use crate::{capture::frame::Kind, config, fixtures::TempDir};
+
+    #[tokio::test]
+    async fn frames_are_written_to_rotating_files() {
+        let dir = TempDir::new("recorder");
+        let conf = config::Recording {
+            path: dir.join(""),
+            max_file_size: Some(100),
+        };
+        let (recorder, writer) = Recorder::spawn_writer(&conf).unwrap();
+        let tap = recorder.tap("binance", "ltcbtc", "orderbooks");
+        let payload = r#"{"lastUpdateId":160,"bids":[["0.0024","10"]],"asks":[]}"#;
+        // frames received within the same microsecond start files that are numbered.
+        for connection in 0..3 {
+            tap.record(connection, Kind::Text, payload);
+        }
+        tap.record(3, Kind::Snapshot, "{}");
+        tap.record_message(3, &Message::Ping(vec![1, 2]));
+        let close = CloseFrame {
+            code: CloseCode::Away,
+            reason: "bye".into(),
+        };
+        tap.record_message(3, &Message::Close(Some(close)));
+        drop((tap, recorder));
+        writer.await.unwrap();
+
+        // a file is full once it holds two frames.
+        let files = capture_files(dir.path()).unwrap();
+        assert_eq!(files.len(), 3);
+        let frames: Vec<_> = files
+            .iter()
+            .flat_map(|file| read_file(file).unwrap())
+            .collect();
+        assert_eq!(frames.len(), 6);
+        assert_eq!(frames[0].payload, payload);
+        assert_eq!(frames[2].exchange, "binance");
+        assert_eq!(frames[2].connection, 2);
+        assert_eq!(frames[3].kind(), Kind::Snapshot);
+        assert_eq!(
+            (frames[4].kind(), frames[4].data.as_slice()),
+            (Kind::Ping, &[1, 2][..])
+        );
+        assert_eq!(frames[5].kind(), Kind::Close);
+        assert_eq!(
+            frames[5].data,
+            [&1001u16.to_be_bytes()[..], b"bye"].concat()
+        );
+        assert!(frames.windows(2).all(|w| w[0].received <= w[1].received));
+    }
+}
@@ -0,0 +1,364 @@
+use log::{debug, info, warn};
+use prost::Message;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, RwLock},
+    time::{sleep_until, Duration, Instant},
+};
+use tokio_stream::StreamExt;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::{
+    aggregator::{self, Outputs},
+    binance::{self, DepthBook, DepthUpdate},
+    bitstamp,
+    capture::{frame::Kind, Frame},
+    config,
+    definitions::{
+        BinanceOrderbookUpdateMessage, FeedEvent, MarketData, Orderbook, Orderbooks, BINANCE,
+        BITSTAMP,
+    },
+    error::ObaggError,
+    history::History,
+    metrics::Metrics,
+    recorder, server,
+    subscriber::{Subscriber, SummaryStream},
+    view::View,
+};
+
+// Summaries queued for the output file. Replays as fast as possible can outpace the file, the
+// queue is large enough that no summary is conflated.
+const OUTPUT_QUEUE: usize = 1 << 20;
+
+// State of a recorded feed, the frames of all its connections are replayed through it.
+#[derive(Default)]
+struct Feed {
+    // local books of the binance diff depth stream, per connection.
+    depth_books: HashMap<u64, DepthBook>,
+    last_sequence: u64,
+}
+
+impl Feed {
+    // Parse a frame, maintaining the local books, with the same code as the live consumers.
+    fn decode(&mut self, conf: &config::Server, frame: &Frame) -> Option<FeedEvent> {
+        let payload = frame.payload.as_str();
+        let parsed = match (frame.exchange.as_str(), frame.channel.as_str()) {
+            (BINANCE, "trades") => binance::parse_trade(payload).map(FeedEvent::Trade),
+            (BINANCE, _) if conf.depth <= 20 => {
+                binance::parse_reduced_orderbook(payload).map(FeedEvent::Orderbook)
+            }
+            (BINANCE, _) => {
+                let book = self
+                    .depth_books
+                    .entry(frame.connection)
+                    .or_insert_with(|| DepthBook::new(conf.depth));
+                if frame.kind() == Kind::Snapshot {
+                    if let Err(e) = book.snapshot(payload) {
+                        warn!("Invalid binance snapshot skipped : {}", e);
+                    }
+                    return None;
+                }
+                // an out of sequence update is followed by the snapshot the live consumer fetched.
+                match serde_json::from_str::<BinanceOrderbookUpdateMessage>(payload) {
+                    Ok(update) => match book.update(update) {
+                        DepthUpdate::Orderbook(orderbook) => Ok(FeedEvent::Orderbook(orderbook)),
+                        DepthUpdate::Stale | DepthUpdate::OutOfSequence => return None,
+                    },
+                    Err(e) => Err(e),
+                }
+            }
+            (_, "trades") => bitstamp::parse_trade(payload).map(FeedEvent::Trade),
+            _ => bitstamp::parse_orderbook(payload, conf.depth).map(FeedEvent::Orderbook),
+        };
+        match parsed {
+            Ok(event) => Some(event),
+            Err(e) => {
+                debug!("Frame skipped. {}: msg {}", e, payload);
+                None
+            }
+        }
+    }
+}
+
+fn wrap(
+    conf: &config::Server,
+    exchange: &'static str,
+    ticker: &str,
+    orderbook: Orderbook,
+) -> Orderbooks {
+    match exchange {
+        _ if ticker != conf.ticker => Orderbooks::Market {
+            exchange,
+            ticker: ticker.into(),
+            orderbook,
+        },
+        BINANCE => Orderbooks::Binance(orderbook),
+        _ => Orderbooks::Bitstamp(orderbook),
+    }
+}
+
+// Feed the frames of the capture files to the aggregator, paced at speed times the recorded pace,
+// or as fast as possible when speed is 0.
+async fn play(
+    conf: &config::Server,
+    files: &[PathBuf],
+    speed: f64,
+    tx: &mpsc::Sender<Result<MarketData, Status>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut feeds: HashMap<(String, String, String), Feed> = HashMap::new();
+    let mut clock: Option<(u64, Instant)> = None;
+    for file in files {
+        info!("Replaying {}.", file.display());
+        for frame in recorder::read_file(file)? {
+            if speed > 0.0 {
+                let (first, start) = *clock.get_or_insert((frame.received, Instant::now()));
+                let offset = Duration::from_micros(frame.received.saturating_sub(first));
+                sleep_until(start + offset.div_f64(speed)).await;
+            }
+            // the control and binary frames are only captured for diagnosis.
+            if !matches!(frame.kind(), Kind::Text | Kind::Snapshot) {
+                continue;
+            }
+            let exchange = match frame.exchange.as_str() {
+                BINANCE => BINANCE,
+                BITSTAMP => BITSTAMP,
+                _ => {
+                    warn!("Frame of unknown exchange {} skipped.", frame.exchange);
+                    continue;
+                }
+            };
+            let key = (
+                frame.exchange.clone(),
+                frame.ticker.clone(),
+                frame.channel.clone(),
+            );
+            let feed = feeds.entry(key).or_default();
+            let Some(mut event) = feed.decode(conf, &frame) else {
+                continue;
+            };
+            if let FeedEvent::Orderbook(orderbook) = &mut event {
+                orderbook.received = frame.received;
+            }
+            // as done by the supervisor, drop updates that another connection already delivered.
+            let sequence = event.sequence();
+            if sequence <= feed.last_sequence {
+                continue;
+            }
+            feed.last_sequence = sequence;
+            let item = match event {
+                FeedEvent::Trade(trade) => MarketData::Trade(trade),
+                FeedEvent::Orderbook(orderbook) => {
+                    MarketData::Book(wrap(conf, exchange, &frame.ticker, orderbook))
+                }
+                _ => continue,
+            };
+            tx.send(Ok(item)).await.map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+// Write the summaries as length-delimited Summary messages. Returns the number written.
+async fn write_summaries(
+    mut summaries: SummaryStream,
+    path: PathBuf,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut written = 0;
+    while let Some(summary) = summaries.next().await {
+        file.write_all(&summary?.encode_length_delimited_to_vec())?;
+        written += 1;
+    }
+    file.flush()?;
+    Ok(written)
+}
+
+// Replay recorded exchange frames through the aggregator. The input is a capture file or a
+// directory of capture files. The summaries of the default view are written to the output file
+// when given, otherwise they are served over gRPC like the live server does.
+pub async fn replay(
+    conf: config::Server,
+    input: &Path,
+    speed: f64,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(ObaggError("Invalid speed : must be a positive number or 0".into()).into());
+    }
+    let files = if input.is_dir() {
+        recorder::capture_files(input)?
+    } else {
+        vec![input.to_path_buf()]
+    };
+    let (tx, mut rx) = mpsc::channel::<Result<MarketData, Status>>(1024);
+
+    let Some(output) = output else {
+        let listener = TcpListener::bind(conf.bind_address).await?;
+        let tx_pool = Arc::new(RwLock::new(HashMap::new()));
+        let server_future = server::serve(&conf, listener, rx, tx_pool, None)?;
+        let feed = async move {
+            play(&conf, &files, speed, &tx).await?;
+            info!("Replay complete, serving the last aggregated book.");
+            // keep the aggregator input open.
+            std::future::pending::<()>().await;
+            Ok::<(), Box<dyn Error + Send + Sync>>(())
+        };
+        tokio::select! {
+            res = server_future => res?,
+            res = feed => res?,
+        }
+        return Ok(());
+    };
+
+    let tx_pool = RwLock::new(HashMap::new());
+    let (subscriber, summaries) = Subscriber::new(OUTPUT_QUEUE, None, View::default());
+    tx_pool.write().await.insert(Uuid::new_v4(), subscriber);
+    let writer = tokio::spawn(write_summaries(summaries, output.to_path_buf()));
+    let feed = async {
+        let tx = tx;
+        play(&conf, &files, speed, &tx).await
+    };
+    let (outputs, metrics) = (Outputs::new(), Metrics::new());
+    let history = conf
+        .history
+        .as_ref()
+        .map(|h| History::spawn(h, &conf.ticker, true))
+        .transpose()?;
+    let aggregate = aggregator::aggregate_orderbooks(
+        &conf,
+        &mut rx,
+        &tx_pool,
+        &outputs,
+        history.as_ref(),
+        None,
+        &metrics,
+    );
+    let (played, aggregated) = tokio::join!(feed, aggregate);
+    played?;
+    aggregated?;
+    if let Some(history) = history {
+        history.close().await;
+    }
+    // closes the summary stream once the writer has drained it.
+    drop(tx_pool);
+    let written = writer.await??;
+    info!(
+        "Replay complete, {} summaries written to {}.",
+        written,
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::replay;
+    use crate::{
+        capture::frame::Kind,
+        config,
+        fixtures::TempDir,
+        mock,
+        orderbook::Summary,
+        recorder::{self, Recorder},
+    };
+
+    fn binance(update_id: u64, bid: &str) -> String {
+        mock::binance_depth(update_id, &[(bid, "1.0")], &[("0.0030", "2.0")])
+    }
+
+    #[tokio::test]
+    async fn replay_recorded_frames_to_file() {
+        let dir = TempDir::new("replay");
+        let conf = config::Recording {
+            path: dir.join("captures"),
+            max_file_size: None,
+        };
+        let (recorder, writer) = Recorder::spawn_writer(&conf).unwrap();
+        let binance_tap = recorder.tap("binance", "ltcbtc", "orderbooks");
+        let bitstamp_tap = recorder.tap("bitstamp", "ltcbtc", "orderbooks");
+        binance_tap.record(0, Kind::Text, &binance(1, "0.0020"));
+        // the hot-standby connection delivers the same update.
+        binance_tap.record(1, Kind::Text, &binance(1, "0.0020"));
+        bitstamp_tap.record(
+            0,
+            Kind::Text,
+            r#"{"event":"bts:subscription_succeeded","channel":"order_book_ltcbtc","data":{}}"#,
+        );
+        bitstamp_tap.record(
+            0,
+            Kind::Text,
+            r#"{"data":{"timestamp":"1661585367","microtimestamp":"1661585367425575",
+                "bids":[["0.0021","3.0"]],"asks":[["0.0031","1.0"]]},
+                "channel":"order_book_ltcbtc","event":"data"}"#,
+        );
+        binance_tap.record(0, Kind::Text, &binance(2, "0.0022"));
+        drop((binance_tap, bitstamp_tap, recorder));
+        writer.await.unwrap();
+
+        let server = config::Server::builder()
+            .with(|conf| {
+                conf.history = Some(config::History {
+                    path: dir.join("history"),
+                    interval: None,
+                })
+            })
+            .build();
+        let output = dir.path().join("summaries.bin");
+        replay(server, &dir.path().join("captures"), 0.0, Some(&output))
+            .await
+            .unwrap();
+
+        let buf = std::fs::read(&output).unwrap();
+        let mut buf = buf.as_slice();
+        let mut summaries = vec![];
+        while !buf.is_empty() {
+            summaries.push(Summary::decode_length_delimited(&mut buf).unwrap());
+        }
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0].bids[0].price, 0.002);
+        assert_eq!(summaries[1].bids[0].exchange, "bitstamp");
+        assert_eq!(summaries[2].bids[0].exchange, "binance");
+        assert_eq!(summaries[2].bids[0].price, 0.0022);
+
+        // the history rows are timed by the frames the books were built from, none is dropped.
+        let frames: Vec<_> = recorder::capture_files(&dir.path().join("captures"))
+            .unwrap()
+            .iter()
+            .flat_map(|file| recorder::read_file(file).unwrap())
+            .collect();
+        let mut rows = vec![];
+        for partition in std::fs::read_dir(dir.path().join("history")).unwrap() {
+            for file in std::fs::read_dir(partition.unwrap().path()).unwrap() {
+                let csv = std::fs::read_to_string(file.unwrap().path()).unwrap();
+                rows.extend(csv.lines().skip(1).map(String::from));
+            }
+        }
+        // (sequence, timestamp) of the written books.
+        let mut books: Vec<(u64, u64)> = rows
+            .iter()
+            .map(|row| {
+                let columns: Vec<&str> = row.split(',').collect();
+                (columns[1].parse().unwrap(), columns[0].parse().unwrap())
+            })
+            .collect();
+        books.sort();
+        books.dedup();
+        let received = |i: usize| frames[i].received;
+        assert_eq!(
+            books,
+            vec![(1, received(0)), (2, received(3)), (3, received(4))]
+        );
+        assert_eq!(rows.len(), 2 + 4 + 4);
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    config,
+    definitions::Orderbook,
+    error::ObaggError,
+    implied,
+    orderbook::{ChildOrder, Level, RoutePlan, RouteRequest, Side},
+};
+
+pub fn validate(req: &RouteRequest) -> Result<(), ObaggError> {
+    if !req.quantity.is_finite() || req.quantity <= 0.0 {
+        return Err(ObaggError(
+            "Invalid quantity : must be a positive number".into(),
+        ));
+    }
+    for (exchange, participation) in &req.max_participation {
+        if !(*participation > 0.0 && *participation <= 1.0) {
+            return Err(ObaggError(format!(
+                "Invalid max_participation for {exchange} : must be within (0, 1]"
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn taker_fee(conf: &config::Server, exchange: &str) -> f64 {
+    conf.exchanges
+        .get(exchange)
+        .and_then(|e| e.taker_fee)
+        .unwrap_or(0.0)
+}
+
+// Split a parent order across exchanges by walking the levels of the aggregated book in order of
+// their price including the taker fee, within the participation cap of each exchange. Child
+// orders below the minimum order size of their exchange cannot be sent, so the smallest one is
+// dropped and its quantity rerouted to the other exchanges until every child order is valid.
+// Implied levels are not quoted by a venue orders can be sent to, they are never routed to.
+pub fn plan(
+    book: &Orderbook,
+    req: &RouteRequest,
+    conf: &config::Server,
+) -> Result<RoutePlan, ObaggError> {
+    if book.bids.is_empty() && book.asks.is_empty() {
+        return Err(ObaggError(
+            "Aggregated orderbook is not available yet".into(),
+        ));
+    }
+    let is_buy = req.side() == Side::Buy;
+    let effective_price = |level: &Level| {
+        let fee = taker_fee(conf, &level.exchange);
+        if is_buy {
+            level.price * (1.0 + fee)
+        } else {
+            level.price * (1.0 - fee)
+        }
+    };
+    let side = if is_buy { &book.asks } else { &book.bids };
+    let mut levels: Vec<&Level> = side
+        .values()
+        .filter(|l| !implied::is_implied(&l.exchange))
+        .filter(|l| req.exchanges.is_empty() || req.exchanges.contains(&l.exchange))
+        .collect();
+    levels.sort_by(|a, b| {
+        let ordering = effective_price(a).total_cmp(&effective_price(b));
+        if is_buy {
+            ordering
+        } else {
+            ordering.reverse()
+        }
+    });
+
+    let mut visible: BTreeMap<&str, f64> = BTreeMap::new();
+    for level in &levels {
+        *visible.entry(level.exchange.as_str()).or_default() += level.amount;
+    }
+
+    let mut excluded: HashSet<&str> = HashSet::new();
+    loop {
+        let mut remaining = req.quantity;
+        let mut children: BTreeMap<&str, ChildOrder> = BTreeMap::new();
+        for level in levels
+            .iter()
+            .filter(|l| !excluded.contains(l.exchange.as_str()))
+        {
+            if remaining <= 0.0 {
+                break;
+            }
+            let exchange = level.exchange.as_str();
+            let cap = req
+                .max_participation
+                .get(exchange)
+                .map_or(f64::INFINITY, |p| p * visible[exchange]);
+            let child = children.entry(exchange).or_insert_with(|| ChildOrder {
+                exchange: exchange.into(),
+                ..Default::default()
+            });
+            let quantity = level.amount.min(remaining).min(cap - child.quantity);
+            if quantity <= 0.0 {
+                continue;
+            }
+            child.quantity += quantity;
+            child.notional += quantity * level.price;
+            child.fee += quantity * level.price * taker_fee(conf, exchange);
+            child.limit_price = level.price;
+            remaining -= quantity;
+        }
+        children.retain(|_, child| child.quantity > 0.0);
+
+        let too_small = children
+            .iter()
+            .filter(|(exchange, child)| {
+                conf.instrument(exchange, &conf.ticker).is_some_and(|i| {
+                    i.min_quantity.is_some_and(|min| child.quantity < min)
+                        || i.min_notional.is_some_and(|min| child.notional < min)
+                })
+            })
+            .min_by(|(_, a), (_, b)| a.quantity.total_cmp(&b.quantity))
+            .map(|(exchange, _)| *exchange);
+        if let Some(exchange) = too_small {
+            excluded.insert(exchange);
+            continue;
+        }
+
+        let children: Vec<ChildOrder> = children.into_values().collect();
+        let filled: f64 = children.iter().map(|c| c.quantity).sum();
+        let notional: f64 = children.iter().map(|c| c.notional).sum();
+        let fee: f64 = children.iter().map(|c| c.fee).sum();
+        let (vwap, effective_vwap) = if filled > 0.0 {
+            let cost = if is_buy {
+                notional + fee
+            } else {
+                notional - fee
+            };
+            (notional / filled, cost / filled)
+        } else {
+            (0.0, 0.0)
+        };
+        return Ok(RoutePlan {
+            children,
+            filled_quantity: filled,
+            unfilled_quantity: (req.quantity - filled).max(0.0),
+            vwap,
+            effective_vwap,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+
+    use super::{plan, validate};
+    use crate::{
+        config,
+        definitions::Orderbook,
+        orderbook::{Level, RouteRequest, Side},
+    };
+
+    fn conf() -> config::Server {
+        config::Server::builder()
+            .with(|conf| {
+                conf.exchanges.binance.taker_fee = Some(0.001);
+                conf.exchanges.bitstamp.taker_fee = Some(0.004);
+                conf.instruments = Some(vec![config::Instrument {
+                    exchange: "bitstamp".into(),
+                    ticker: "ltcbtc".into(),
+                    min_quantity: Some(0.5),
+                    min_notional: None,
+                }]);
+            })
+            .build()
+    }
+
+    fn book() -> Orderbook {
+        let level = |exchange: &str, price: i64, amount: f64| {
+            let level = Level {
+                exchange: exchange.into(),
+                price: price as f64,
+                amount,
+                ..Default::default()
+            };
+            (
+                Decimal::from(price * 10) + Decimal::from(exchange.len() as i64),
+                level,
+            )
+        };
+        let mut book = Orderbook::new();
+        book.asks = [
+            level("bitstamp", 100, 1.0),
+            level("binance", 100, 1.0),
+            level("binance", 101, 1.0),
+            level("bitstamp", 101, 10.0),
+        ]
+        .into();
+        book
+    }
+
+    #[test]
+    fn plan_orders_by_fee_adjusted_price() {
+        let conf = conf();
+        let req = RouteRequest {
+            side: Side::Buy as i32,
+            quantity: 2.5,
+            ..Default::default()
+        };
+        validate(&req).unwrap();
+        // once fees are paid the bitstamp level at 100 costs 100.4, between the binance levels at
+        // 100.1 and 101.101.
+        let plan = plan(&book(), &req, &conf).unwrap();
+        assert_eq!(plan.filled_quantity, 2.5);
+        assert_eq!(plan.children.len(), 2);
+        assert_eq!(plan.children[0].exchange, "binance");
+        assert_eq!(plan.children[0].quantity, 1.5);
+        assert_eq!(plan.children[0].limit_price, 101.0);
+        assert_eq!(plan.children[1].exchange, "bitstamp");
+        assert_eq!(plan.children[1].quantity, 1.0);
+    }
+
+    #[test]
+    fn plan_respects_participation_and_min_size() {
+        let conf = conf();
+        let req = RouteRequest {
+            side: Side::Buy as i32,
+            quantity: 2.2,
+            max_participation: HashMap::from([("bitstamp".to_string(), 0.02)]),
+            ..Default::default()
+        };
+        // bitstamp is capped at 0.22, below its 0.5 minimum, so only binance is routed to.
+        let plan = plan(&book(), &req, &conf).unwrap();
+        assert_eq!(plan.children.len(), 1);
+        assert_eq!(plan.children[0].exchange, "binance");
+        assert_eq!(plan.filled_quantity, 2.0);
+        assert!((plan.unfilled_quantity - 0.2).abs() < 1e-9);
+
+        let invalid = RouteRequest {
+            quantity: 1.0,
+            max_participation: HashMap::from([("binance".to_string(), 1.5)]),
+            ..Default::default()
+        };
+        assert!(validate(&invalid).is_err());
+    }
+
+    #[test]
+    fn implied_levels_are_not_routed_to() {
+        let conf = conf();
+        let mut book = book();
+        let implied = Level {
+            exchange: "binance:implied".into(),
+            price: 99.0,
+            amount: 5.0,
+            ..Default::default()
+        };
+        book.asks.insert(Decimal::from(990), implied);
+        for exchanges in [vec![], vec!["binance:implied".to_string()]] {
+            let req = RouteRequest {
+                side: Side::Buy as i32,
+                quantity: 1.0,
+                exchanges,
+                ..Default::default()
+            };
+            let plan = plan(&book, &req, &conf).unwrap();
+            assert!(plan
+                .children
+                .iter()
+                .all(|c| c.exchange != "binance:implied"));
+        }
+    }
+}
@@ -18,15 +18,23 @@ where
     u64::from_str(&s).map_err(de::Error::custom)
 }
 
+pub fn f64_from_str<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    f64::from_str(&s).map_err(de::Error::custom)
+}
+
 pub fn tf64_from_str<'de, D>(deserializer: D) -> Result<(Decimal, f64), D::Error>
 where
     D: Deserializer<'de>,
 {
     let v: Vec<String> = Vec::deserialize(deserializer)?;
     if v.len() != 2 {
-        return Err(serde::de::Error::custom(format!(
-            "Failed to deserialize both price and amount from level. Levels must contain 2 elements."
-        )));
+        return Err(serde::de::Error::custom(
+            "Failed to deserialize both price and amount from level. Levels must contain 2 elements.",
+        ));
     }
     let level = Decimal::from_str(&v[0]).map_err(de::Error::custom);
     let amount = f64::from_str(&v[1]).map_err(de::Error::custom);
@@ -1,88 +1,341 @@
 use futures::{future::*, Future};
 use log::{info, warn};
-use std::{collections::HashMap, error::Error, pin::Pin, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    pin::Pin,
+    sync::Arc,
+};
 
-use tokio::sync::{mpsc, RwLock};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, RwLock},
+    time::Duration,
+};
+use tokio_stream::wrappers::TcpListenerStream;
 use tonic::{transport::Server, Status};
 
 use crate::{
-    aggregator, binance, bitstamp, config, definitions::Orderbooks,
-    grpc::OrderbookAggregatorServer, orderbook,
+    aggregator, binance, bitstamp, check, config,
+    definitions::{MarketData, Orderbook, Orderbooks, BINANCE, BITSTAMP},
+    error::ObaggError,
+    feed,
+    grpc::{OrderbookAggregatorServer, ProducerPool},
+    history::History,
+    metrics::Metrics,
+    orderbook,
+    recorder::Recorder,
+    store::Store,
 };
 
+type ServerFuture = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send>>;
+
 // This server function first launches the gRPC stream server to serve the aggregated orderbook
 // followed by launching websocket clients for each exchange.
 pub async fn server(conf: config::Server) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let tx_pool = HashMap::new();
-    let tx_pool_rwl = RwLock::new(tx_pool);
-    let tx_pool_arc = Arc::new(tx_pool_rwl);
-    let (binance_orderbook_ws_tx, mut aggregator_rx) =
-        mpsc::channel::<Result<Orderbooks, Status>>(1024); // or bounded
+    let listener = TcpListener::bind(conf.bind_address).await?;
+    let tx_pool = Arc::new(RwLock::new(HashMap::new()));
+    server_with_listener(conf, listener, tx_pool).await
+}
+
+// Run the server on a listener that is already bound, e.g. to an ephemeral port, publishing the
+// summaries to the clients of tx_pool.
+pub(crate) async fn server_with_listener(
+    conf: config::Server,
+    listener: TcpListener,
+    tx_pool: ProducerPool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // refuse a configuration the consumers would fail on once spawned.
+    let problems: Vec<String> = check::validate(&conf)
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    if !problems.is_empty() {
+        return Err(ObaggError(format!("Invalid configuration : {}", problems.join(", "))).into());
+    }
+    let (binance_orderbook_ws_tx, aggregator_rx) =
+        mpsc::channel::<Result<MarketData, Status>>(1024); // or bounded
     let bitstamp_orderbook_ws_tx = binance_orderbook_ws_tx.clone();
-    let server = OrderbookAggregatorServer {
-        tx_pool: tx_pool_arc.clone(),
+    let markets_orderbook_ws_tx = binance_orderbook_ws_tx.clone();
+    let trades_ws_tx = binance_orderbook_ws_tx.clone();
+    let markets = aux_markets(&conf)?;
+    let sinks = Sinks {
+        recorder: conf.record.as_ref().map(Recorder::spawn).transpose()?,
+        store: conf
+            .snapshots
+            .as_ref()
+            .map(|s| Store::open(s, &conf.ticker))
+            .transpose()?,
     };
-    let binance_conf = conf.clone();
-    let bitstamp_conf = conf.clone();
-    let aggregator_conf = conf.clone();
 
     // launch the server in the main thread.
-    let server_future: Pin<Box<dyn Future<Output = Result<(), Box<dyn Error + Send + Sync>>>>> =
-        Box::pin(
-            Server::builder()
-                .add_service(
-                    orderbook::orderbook_aggregator_server::OrderbookAggregatorServer::new(server),
-                )
-                .serve(conf.bind_address)
-                .map_err(|e| e.into()),
-        );
-    info!(
-        "Started gRPC Server... Bind Address: {:?}",
-        &conf.bind_address
-    );
+    let server_future = serve(&conf, listener, aggregator_rx, tx_pool, sinks.store.clone())?;
 
     // launch the binance orderbook consumer in a thread
     if conf.exchanges.binance.enable {
-        tokio::spawn(async move {
-            info!("Spawned binance websocket consumer.");
-            if binance_conf.depth <= 20 {
-                while let Ok(_) =
-                    binance::consume_reduced_orderbooks(&binance_conf, &binance_orderbook_ws_tx)
-                        .await
-                {
-                    warn!("Relaunching binance websocket consumer.");
-                }
-            } else {
-                while let Ok(_) =
-                    binance::consume_orderbooks(&binance_conf, &binance_orderbook_ws_tx).await
-                {
-                    warn!("Relaunching binance websocket consumer.");
-                }
-            }
-        });
+        spawn_feed(
+            &conf,
+            BINANCE,
+            conf.ticker.clone(),
+            Channel::Orderbooks(Box::new(Orderbooks::Binance)),
+            binance_orderbook_ws_tx,
+            &sinks,
+        );
     }
 
     // launch the bitstamp orderbook consumer in a thread
     if conf.exchanges.bitstamp.enable {
-        tokio::spawn(async move {
-            while let Ok(_) =
-                bitstamp::consume_orderbooks(&bitstamp_conf, &bitstamp_orderbook_ws_tx).await
-            {
-                warn!("Relaunching bitstamp websocket consumer.");
+        spawn_feed(
+            &conf,
+            BITSTAMP,
+            conf.ticker.clone(),
+            Channel::Orderbooks(Box::new(Orderbooks::Bitstamp)),
+            bitstamp_orderbook_ws_tx,
+            &sinks,
+        );
+    }
+
+    // launch a consumer for each market quoted in another currency, for its rate market and for
+    // the legs of the implied books.
+    for (exchange, ticker) in markets {
+        let wrap = {
+            let ticker = ticker.clone();
+            move |orderbook| Orderbooks::Market {
+                exchange,
+                ticker: ticker.clone(),
+                orderbook,
             }
-        });
+        };
+        spawn_feed(
+            &conf,
+            exchange,
+            ticker,
+            Channel::Orderbooks(Box::new(wrap)),
+            markets_orderbook_ws_tx.clone(),
+            &sinks,
+        );
     }
 
+    // launch the trade consumers of the enabled exchanges, their feeds only deliver trades.
+    if conf.trades.unwrap_or(false) {
+        if conf.exchanges.binance.enable {
+            spawn_feed(
+                &conf,
+                BINANCE,
+                conf.ticker.clone(),
+                Channel::Trades,
+                trades_ws_tx.clone(),
+                &sinks,
+            );
+        }
+        if conf.exchanges.bitstamp.enable {
+            spawn_feed(
+                &conf,
+                BITSTAMP,
+                conf.ticker.clone(),
+                Channel::Trades,
+                trades_ws_tx.clone(),
+                &sinks,
+            );
+        }
+    }
+
+    server_future.await?;
+    Ok(())
+}
+
+// Launch the orderbook aggregator on the books received from rx, and the gRPC server serving its
+// outputs on the listener. The summaries are published to the clients of tx_pool.
+pub(crate) fn serve(
+    conf: &config::Server,
+    listener: TcpListener,
+    mut aggregator_rx: mpsc::Receiver<Result<MarketData, Status>>,
+    tx_pool: ProducerPool,
+    store: Option<Store>,
+) -> Result<ServerFuture, Box<dyn Error + Send + Sync>> {
+    let history = conf
+        .history
+        .as_ref()
+        .map(|h| History::spawn(h, &conf.ticker, false))
+        .transpose()?;
+    let metrics = Arc::new(Metrics::new());
+    let outputs = Arc::new(aggregator::Outputs::new());
+    let server = OrderbookAggregatorServer {
+        conf: conf.clone(),
+        tx_pool: tx_pool.clone(),
+        outputs: outputs.clone(),
+        store: store.clone(),
+        metrics: metrics.clone(),
+    };
+    let aggregator_conf = conf.clone();
+    let bind_address = listener.local_addr()?;
+
+    let server_future: ServerFuture = Box::pin(
+        Server::builder()
+            .add_service(
+                orderbook::orderbook_aggregator_server::OrderbookAggregatorServer::new(server),
+            )
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .map_err(|e| e.into()),
+    );
+    info!("Started gRPC Server... Bind Address: {:?}", &bind_address);
+
     // launch the orderbook aggregator in a thread
     tokio::spawn(async move {
-        while let Ok(_) =
-            aggregator::aggregate_orderbooks(&aggregator_conf, &mut aggregator_rx, &tx_pool_arc)
-                .await
+        // the aggregator returns Ok once its input is closed, relaunching it then would spin on
+        // the closed channel.
+        while let Err(e) = aggregator::aggregate_orderbooks(
+            &aggregator_conf,
+            &mut aggregator_rx,
+            &tx_pool,
+            &outputs,
+            history.as_ref(),
+            store.as_ref(),
+            &metrics,
+        )
+        .await
         {
-            warn!("Relaunching bitstamp websocket consumer.");
+            warn!("Relaunching orderbook aggregator : {}", e);
         }
     });
 
-    server_future.await?;
-    Ok(())
+    Ok(server_future)
+}
+
+// The (exchange, ticker) markets the quote conversions and implied books are consumed from, each
+// listed once.
+fn aux_markets(
+    conf: &config::Server,
+) -> Result<BTreeSet<(&'static str, String)>, Box<dyn Error + Send + Sync>> {
+    let mut wanted = vec![];
+    for quote in conf.quotes.iter().flatten() {
+        wanted.extend(quote.exchanges.iter().map(|e| (e, &quote.ticker)));
+        wanted.extend(quote.rate.exchanges.iter().map(|e| (e, &quote.rate.ticker)));
+    }
+    for implied in conf.implied.iter().flatten() {
+        wanted.push((&implied.exchange, &implied.base));
+        wanted.push((&implied.exchange, &implied.quote));
+    }
+    let mut markets = BTreeSet::new();
+    for (exchange, ticker) in wanted {
+        let exchange = match exchange.as_str() {
+            BINANCE => BINANCE,
+            BITSTAMP => BITSTAMP,
+            _ => return Err(ObaggError(format!("Unknown exchange : {exchange}")).into()),
+        };
+        if conf.exchanges.get(exchange).is_some_and(|e| !e.enable) {
+            warn!(
+                "{} is disabled, {} {} will not be consumed.",
+                exchange, exchange, ticker
+            );
+            continue;
+        }
+        markets.insert((exchange, ticker.clone()));
+    }
+    Ok(markets)
+}
+
+// The channel of a market a feed consumes, the books of a book feed are wrapped as the market
+// they are the books of.
+enum Channel {
+    Orderbooks(Box<dyn Fn(Orderbook) -> Orderbooks + Send + Sync>),
+    Trades,
+}
+
+impl Channel {
+    fn name(&self) -> &'static str {
+        match self {
+            Channel::Orderbooks(_) => "orderbooks",
+            Channel::Trades => "trades",
+        }
+    }
+}
+
+// Where the feeds report to: the recorder of their frames and the store of their status.
+struct Sinks {
+    recorder: Option<Recorder>,
+    store: Option<Store>,
+}
+
+impl Sinks {
+    fn observers(
+        &self,
+        exchange: &'static str,
+        ticker: &str,
+        channel: &Channel,
+    ) -> feed::Observers {
+        feed::Observers {
+            tap: self
+                .recorder
+                .as_ref()
+                .map(|r| r.tap(exchange, ticker, channel.name())),
+            status: self
+                .store
+                .as_ref()
+                .map(|s| s.status_log(exchange, ticker, channel.name())),
+        }
+    }
+}
+
+// Spawn the supervised websocket consumers of a market channel on an exchange.
+fn spawn_feed(
+    conf: &config::Server,
+    exchange: &'static str,
+    ticker: String,
+    channel: Channel,
+    tx: mpsc::Sender<Result<MarketData, Status>>,
+    sinks: &Sinks,
+) {
+    let observers = sinks.observers(exchange, &ticker, &channel);
+    let exchange_conf = conf.exchanges.get(exchange);
+    let connections = exchange_conf.and_then(|e| e.connections).unwrap_or(1);
+    let reconnect_period = exchange_conf
+        .and_then(|e| e.reconnect_period)
+        .map(Duration::from_secs);
+    let conf = Arc::new(conf.clone());
+    tokio::spawn(async move {
+        info!("Spawned {} {} websocket consumer.", exchange, ticker);
+        let ticker = Arc::new(ticker);
+        let trades = matches!(channel, Channel::Trades);
+        let connect = move |tx| {
+            let conf = conf.clone();
+            let ticker = ticker.clone();
+            async move {
+                match exchange {
+                    BINANCE if trades => binance::consume_trades(&conf, &ticker, &tx).await,
+                    BINANCE if conf.depth <= 20 => {
+                        binance::consume_reduced_orderbooks(&conf, &ticker, &tx).await
+                    }
+                    BINANCE => binance::consume_orderbooks(&conf, &ticker, &tx).await,
+                    _ if trades => bitstamp::consume_trades(&conf, &ticker, &tx).await,
+                    _ => bitstamp::consume_orderbooks(&conf, &ticker, &tx).await,
+                }
+            }
+        };
+        match channel {
+            Channel::Orderbooks(wrap) => {
+                feed::supervise(
+                    exchange,
+                    connect,
+                    observers,
+                    connections,
+                    reconnect_period,
+                    wrap,
+                    tx,
+                )
+                .await
+            }
+            Channel::Trades => {
+                feed::supervise_trades(
+                    exchange,
+                    connect,
+                    observers,
+                    connections,
+                    reconnect_period,
+                    tx,
+                )
+                .await
+            }
+        }
+    });
 }
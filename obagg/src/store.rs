@@ -0,0 +1,394 @@
+use log::{error, info, warn};
+use prost::Message;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::{
+    error::Error,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    config,
+    orderbook::{BookSnapshot, FeedStatus, Summary},
+    utils,
+};
+
+const DEFAULT_INTERVAL: u64 = 1000;
+// Writes waiting for the database, beyond which new ones are dropped rather than slowing down the
+// aggregator and the feeds.
+const QUEUE: usize = 4096;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS snapshots (
+        timestamp INTEGER NOT NULL,
+        symbol TEXT NOT NULL,
+        sequence INTEGER NOT NULL,
+        summary BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS snapshots_symbol_timestamp ON snapshots (symbol, timestamp);
+    CREATE TABLE IF NOT EXISTS feed_status (
+        timestamp INTEGER NOT NULL,
+        exchange TEXT NOT NULL,
+        ticker TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        up INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS feed_status_timestamp ON feed_status (timestamp);
+";
+
+// The last transition of each feed at a time.
+const FEEDS: &str = "
+    SELECT exchange, ticker, channel, up, max(timestamp) FROM feed_status
+    WHERE timestamp <= ?1
+    GROUP BY exchange, ticker, channel
+    ORDER BY exchange, ticker, channel
+";
+
+// The snapshot nearest to a time, either side of it.
+const NEAREST: &str = "
+    SELECT timestamp, sequence, summary FROM (
+        SELECT * FROM (
+            SELECT timestamp, sequence, summary FROM snapshots
+            WHERE symbol = ?1 AND timestamp <= ?2 ORDER BY timestamp DESC LIMIT 1
+        )
+        UNION ALL
+        SELECT * FROM (
+            SELECT timestamp, sequence, summary FROM snapshots
+            WHERE symbol = ?1 AND timestamp > ?2 ORDER BY timestamp LIMIT 1
+        )
+    )
+    ORDER BY abs(timestamp - ?2) LIMIT 1
+";
+
+struct Snapshot {
+    // time the frame the book was built from was received, in microseconds since the epoch.
+    timestamp: u64,
+    sequence: u64,
+    summary: Summary,
+}
+
+enum Write {
+    Snapshot(Snapshot),
+    Status {
+        timestamp: u64,
+        exchange: &'static str,
+        ticker: Arc<str>,
+        channel: &'static str,
+        up: bool,
+    },
+}
+
+// Handle to the SQLite store of the book snapshots and of the status transitions of the feeds.
+// Writes go through a single writer thread, reads open their own connection.
+#[derive(Clone)]
+pub struct Store {
+    path: String,
+    tx: mpsc::SyncSender<Write>,
+}
+
+impl Store {
+    pub fn open(
+        conf: &config::Snapshots,
+        symbol: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut writer = StoreWriter::open(conf, symbol)?;
+        info!("Storing book snapshots to {}.", conf.path);
+        let (tx, rx) = mpsc::sync_channel::<Write>(QUEUE);
+        tokio::task::spawn_blocking(move || writer.run(rx));
+        Ok(Store {
+            path: conf.path.clone(),
+            tx,
+        })
+    }
+
+    // Bind the store to a feed, for its supervisor to log the transitions of the feed.
+    pub fn status_log(
+        &self,
+        exchange: &'static str,
+        ticker: &str,
+        channel: &'static str,
+    ) -> StatusLog {
+        StatusLog {
+            store: self.clone(),
+            exchange,
+            ticker: ticker.into(),
+            channel,
+        }
+    }
+
+    // Queue the summary of the aggregated book with the given sequence, timed by the frame it was
+    // built from. The writer keeps the latest one and stores it once per interval.
+    pub fn snapshot(&self, timestamp: u64, sequence: u64, summary: Summary) {
+        let snapshot = Snapshot {
+            timestamp,
+            sequence,
+            summary,
+        };
+        self.send(Write::Snapshot(snapshot));
+    }
+
+    fn send(&self, write: Write) {
+        if self.tx.try_send(write).is_err() {
+            warn!("Snapshot store is falling behind, write dropped.");
+        }
+    }
+
+    // The stored snapshot of the symbol nearest to the timestamp, in microseconds.
+    pub async fn book_at(
+        &self,
+        symbol: &str,
+        timestamp: u64,
+    ) -> Result<Option<BookSnapshot>, Box<dyn Error + Send + Sync>> {
+        let path = self.path.clone();
+        let symbol = symbol.to_string();
+        tokio::task::spawn_blocking(move || book_at(&path, &symbol, timestamp)).await?
+    }
+}
+
+// Store bound to a feed, given to its supervisor.
+#[derive(Clone)]
+pub struct StatusLog {
+    store: Store,
+    exchange: &'static str,
+    ticker: Arc<str>,
+    channel: &'static str,
+}
+
+impl StatusLog {
+    pub fn record(&self, up: bool) {
+        self.store.send(Write::Status {
+            timestamp: utils::now_micros(),
+            exchange: self.exchange,
+            ticker: self.ticker.clone(),
+            channel: self.channel,
+            up,
+        });
+    }
+}
+
+struct StoreWriter {
+    conn: Connection,
+    symbol: String,
+    // time between two snapshots, in microseconds.
+    interval: u64,
+    // timestamp of the last stored snapshot.
+    last: Option<u64>,
+    // latest book, not stored yet.
+    pending: Option<Snapshot>,
+}
+
+impl StoreWriter {
+    fn open(conf: &config::Snapshots, symbol: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(&conf.path)?;
+        // readers do not block the writer.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        // feeds that were up when the last run stopped went down with it.
+        conn.execute(
+            &format!(
+                "INSERT INTO feed_status
+                 SELECT ?1, exchange, ticker, channel, 0 FROM ({FEEDS}) WHERE up"
+            ),
+            params![utils::now_micros()],
+        )?;
+        Ok(StoreWriter {
+            conn,
+            symbol: symbol.into(),
+            interval: conf.interval.unwrap_or(DEFAULT_INTERVAL) * 1000,
+            last: None,
+            pending: None,
+        })
+    }
+
+    fn run(&mut self, rx: mpsc::Receiver<Write>) {
+        loop {
+            let received = match self.due() {
+                Some(wait) => rx.recv_timeout(wait),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let result = match received {
+                Ok(write) => self.write(write),
+                Err(RecvTimeoutError::Timeout) => self.flush(),
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Err(e) = self.flush() {
+                        error!("Error storing book snapshot : {}", e);
+                    }
+                    return;
+                }
+            };
+            if let Err(e) = result {
+                error!("Error writing snapshot store, storing stopped : {}", e);
+                return;
+            }
+        }
+    }
+
+    fn write(&mut self, write: Write) -> rusqlite::Result<()> {
+        match write {
+            Write::Snapshot(snapshot) => {
+                // the latest book is stored once the interval has passed, so that the last
+                // snapshot before any time is the book that was current then.
+                let due = self
+                    .last
+                    .is_none_or(|t| snapshot.timestamp >= t + self.interval);
+                self.pending = Some(snapshot);
+                if due {
+                    self.flush()?;
+                }
+            }
+            Write::Status {
+                timestamp,
+                exchange,
+                ticker,
+                channel,
+                up,
+            } => {
+                self.conn.execute(
+                    "INSERT INTO feed_status VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![timestamp, exchange, &*ticker, channel, up],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> rusqlite::Result<()> {
+        let Some(snapshot) = self.pending.take() else {
+            return Ok(());
+        };
+        self.conn.execute(
+            "INSERT INTO snapshots VALUES (?1, ?2, ?3, ?4)",
+            params![
+                snapshot.timestamp,
+                self.symbol,
+                snapshot.sequence,
+                snapshot.summary.encode_to_vec()
+            ],
+        )?;
+        self.last = Some(snapshot.timestamp);
+        Ok(())
+    }
+
+    // Time left before the pending book is stored.
+    fn due(&self) -> Option<Duration> {
+        self.pending.as_ref()?;
+        let due = self.last? + self.interval;
+        Some(Duration::from_micros(
+            due.saturating_sub(utils::now_micros()),
+        ))
+    }
+}
+
+fn book_at(
+    path: &str,
+    symbol: &str,
+    timestamp: u64,
+) -> Result<Option<BookSnapshot>, Box<dyn Error + Send + Sync>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let timestamp = timestamp.min(i64::MAX as u64);
+    let nearest = conn
+        .query_row(NEAREST, params![symbol, timestamp], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, Vec<u8>>(2)?))
+        })
+        .optional()?;
+    let Some((taken, sequence, summary)) = nearest else {
+        return Ok(None);
+    };
+    let feeds = conn
+        .prepare(FEEDS)?
+        .query_map(params![taken], |row| {
+            Ok(FeedStatus {
+                exchange: row.get(0)?,
+                ticker: row.get(1)?,
+                channel: row.get(2)?,
+                up: row.get(3)?,
+                since: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(Some(BookSnapshot {
+        symbol: symbol.into(),
+        timestamp: taken,
+        sequence,
+        summary: Some(Summary::decode(summary.as_slice())?),
+        feeds,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{book_at, Snapshot, StoreWriter, Write};
+    use crate::{
+        config,
+        fixtures::{summary, TempDir},
+    };
+
+    #[test]
+    fn nearest_snapshot_is_returned_with_feed_status() {
+        let dir = TempDir::new("store");
+        let path = dir.join("obagg.db");
+        let conf = config::Snapshots {
+            path: path.clone(),
+            interval: Some(1000),
+        };
+        let mut writer = StoreWriter::open(&conf, "ltcbtc").unwrap();
+        let start = 1_661_585_367_000_000;
+        let status = |timestamp, exchange, up| Write::Status {
+            timestamp,
+            exchange,
+            ticker: "ltcbtc".into(),
+            channel: "orderbooks",
+            up,
+        };
+        writer.write(status(start - 10, "binance", true)).unwrap();
+        writer.write(status(start - 5, "bitstamp", true)).unwrap();
+        for (offset, sequence, bid) in [
+            (0, 1, 0.0009),
+            // within the interval, kept until the next snapshot is due.
+            (300_000, 2, 0.0008),
+            (5_000_000, 3, 0.0007),
+        ] {
+            let snapshot = Snapshot {
+                timestamp: start + offset,
+                sequence,
+                summary: summary(bid),
+            };
+            writer.write(Write::Snapshot(snapshot)).unwrap();
+        }
+        assert_eq!(writer.due(), None);
+        writer
+            .write(status(start + 4_000_000, "bitstamp", false))
+            .unwrap();
+
+        let book = book_at(&path, "ltcbtc", start + 100_000).unwrap().unwrap();
+        assert_eq!((book.timestamp, book.sequence), (start, 1));
+        assert_eq!(book.summary.unwrap().bids[0].price, 0.0009);
+        assert!(book.feeds.iter().all(|f| f.up));
+        assert_eq!(book.feeds.len(), 2);
+
+        let book = book_at(&path, "ltcbtc", start + 4_500_000)
+            .unwrap()
+            .unwrap();
+        assert_eq!(book.sequence, 3);
+        assert_eq!(book.feeds[1].exchange, "bitstamp");
+        assert!(!book.feeds[1].up);
+        assert_eq!(book.feeds[1].since, start + 4_000_000);
+
+        assert!(book_at(&path, "ethbtc", start).unwrap().is_none());
+        drop(writer);
+        // feeds left up are marked down when the store is opened again.
+        let writer = StoreWriter::open(&conf, "ltcbtc").unwrap();
+        let down: Vec<String> = writer
+            .conn
+            .prepare("SELECT exchange FROM feed_status WHERE NOT up ORDER BY timestamp")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(down, ["bitstamp", "binance"]);
+    }
+}
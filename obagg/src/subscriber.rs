@@ -0,0 +1,319 @@
+use async_stream::stream;
+use log::warn;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, error::TryRecvError, error::TrySendError},
+        watch,
+    },
+    time::{sleep_until, Duration, Instant},
+};
+use tokio_stream::Stream;
+use tonic::Status;
+
+use crate::{orderbook::Summary, view::View};
+
+pub type SummaryStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send>>;
+
+// Summary retained for a client whose queue is full.
+#[derive(Clone)]
+enum Latest {
+    Empty,
+    Summary(Summary),
+    // the client was disconnected by the slow client policy.
+    Evicted(String),
+}
+
+impl Latest {
+    fn into_item(self) -> Option<Result<Summary, Status>> {
+        match self {
+            Latest::Empty => None,
+            Latest::Summary(summary) => Some(Ok(summary)),
+            Latest::Evicted(message) => Some(Err(Status::resource_exhausted(message))),
+        }
+    }
+}
+
+pub enum Delivery {
+    Sent,
+    // the client queue was full, the summary replaced the previously conflated one.
+    Conflated(u64),
+    Closed,
+}
+
+// The aggregator side of a gRPC client connection. Summaries are delivered without ever waiting
+// on the client: they are queued while the client keeps up, and once its queue is full only the
+// latest summary is retained until the client has drained its queue. Clients that requested a
+// min_interval are always conflated and receive at most one summary per interval, they lag when
+// they do not take the latest summary once their interval is over.
+pub struct Subscriber {
+    pub view: View,
+    tx: mpsc::Sender<Summary>,
+    latest: watch::Sender<Latest>,
+    // number of consecutive summaries conflated since the last successful send, reset by the
+    // stream of a throttled client when it takes a summary.
+    lag: Arc<AtomicU64>,
+    throttled: bool,
+    // end of the interval the throttled client is waiting out since it took a summary.
+    resting: Arc<Mutex<Instant>>,
+}
+
+impl Subscriber {
+    pub fn new(buffer: usize, min_interval: Option<Duration>, view: View) -> (Self, SummaryStream) {
+        let (tx, rx) = mpsc::channel(buffer.max(1));
+        let (latest, latest_rx) = watch::channel(Latest::Empty);
+        let subscriber = Subscriber {
+            view,
+            tx,
+            latest,
+            lag: Arc::new(AtomicU64::new(0)),
+            throttled: min_interval.is_some(),
+            resting: Arc::new(Mutex::new(Instant::now())),
+        };
+        let stream: SummaryStream = match min_interval {
+            Some(interval) => Box::pin(throttled_stream(
+                latest_rx,
+                interval,
+                subscriber.lag.clone(),
+                subscriber.resting.clone(),
+            )),
+            None => Box::pin(summary_stream(rx, latest_rx)),
+        };
+        (subscriber, stream)
+    }
+
+    pub fn deliver(&self, summary: &Summary) -> Delivery {
+        if self.throttled {
+            if self.latest.is_closed() {
+                return Delivery::Closed;
+            }
+            self.latest.send_replace(Latest::Summary(summary.clone()));
+            // the summaries replaced while the client rests are conflated by the throttling.
+            if Instant::now() < *self.resting.lock().unwrap() {
+                return Delivery::Sent;
+            }
+            return match self.lag.fetch_add(1, Ordering::Relaxed) {
+                0 => Delivery::Sent,
+                lag => Delivery::Conflated(lag),
+            };
+        }
+        if self.lag.load(Ordering::Relaxed) > 0 {
+            // a newer summary is about to be queued, the conflated one is obsolete. This must be
+            // cleared before queueing so the client never receives it after the newer one.
+            self.latest.send_replace(Latest::Empty);
+        }
+        match self.tx.try_send(summary.clone()) {
+            Ok(_) => {
+                self.lag.store(0, Ordering::Relaxed);
+                Delivery::Sent
+            }
+            Err(TrySendError::Full(item)) => {
+                self.latest.send_replace(Latest::Summary(item));
+                Delivery::Conflated(self.lag.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+            Err(TrySendError::Closed(_)) => Delivery::Closed,
+        }
+    }
+
+    // Terminate the client stream with a resource exhausted status once its queue has been
+    // drained.
+    pub fn evict(&self, message: String) {
+        self.latest.send_replace(Latest::Evicted(message));
+    }
+}
+
+fn summary_stream(
+    mut rx: mpsc::Receiver<Summary>,
+    mut latest_rx: watch::Receiver<Latest>,
+) -> impl Stream<Item = Result<Summary, Status>> {
+    stream! {
+        loop {
+            // queued summaries are always older than the conflated one.
+            match rx.try_recv() {
+                Ok(summary) => {
+                    yield Ok(summary);
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    let item = latest_rx.borrow_and_update().clone().into_item();
+                    if let Some(item) = item {
+                        yield item;
+                    }
+                    break;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+            if latest_rx.has_changed().unwrap_or(false) {
+                let item = latest_rx.borrow_and_update().clone().into_item();
+                if let Some(item) = item {
+                    let is_err = item.is_err();
+                    yield item;
+                    if is_err {
+                        break;
+                    }
+                }
+                continue;
+            }
+            // on a change, or once the subscriber has been dropped, the next pass picks it up.
+            let item = tokio::select! {
+                item = rx.recv() => item,
+                _ = latest_rx.changed() => None,
+            };
+            if let Some(summary) = item {
+                yield Ok(summary);
+            }
+        }
+    }
+}
+
+// Deliver the latest summary at most once per interval.
+fn throttled_stream(
+    mut latest_rx: watch::Receiver<Latest>,
+    interval: Duration,
+    lag: Arc<AtomicU64>,
+    resting: Arc<Mutex<Instant>>,
+) -> impl Stream<Item = Result<Summary, Status>> {
+    stream! {
+        while latest_rx.changed().await.is_ok() {
+            let item = latest_rx.borrow_and_update().clone().into_item();
+            lag.store(0, Ordering::Relaxed);
+            if let Some(item) = item {
+                let rested = Instant::now() + interval;
+                *resting.lock().unwrap() = rested;
+                let is_err = item.is_err();
+                yield item;
+                if is_err {
+                    break;
+                }
+                sleep_until(rested).await;
+            }
+        }
+    }
+}
+
+// Stream the events broadcast by the aggregator, such as trades, to a gRPC client. A client that
+// lags skips the events it missed.
+pub fn broadcast_stream<T: Clone + Send + 'static>(
+    mut rx: broadcast::Receiver<T>,
+    name: &'static str,
+) -> impl Stream<Item = Result<T, Status>> {
+    stream! {
+        loop {
+            match rx.recv().await {
+                Ok(event) => yield Ok(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("{} client lagged, {} events skipped.", name, skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{sleep, timeout, Duration, Instant};
+    use tokio_stream::StreamExt;
+
+    use super::{Delivery, Subscriber};
+    use crate::{orderbook::Summary, view::View};
+
+    fn summary(spread: f64) -> Summary {
+        Summary {
+            spread,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_client_is_conflated() {
+        let (subscriber, mut stream) = Subscriber::new(2, None, View::default());
+        assert!(matches!(subscriber.deliver(&summary(1.0)), Delivery::Sent));
+        assert!(matches!(subscriber.deliver(&summary(2.0)), Delivery::Sent));
+        assert!(matches!(
+            subscriber.deliver(&summary(3.0)),
+            Delivery::Conflated(1)
+        ));
+        assert!(matches!(
+            subscriber.deliver(&summary(4.0)),
+            Delivery::Conflated(2)
+        ));
+
+        let mut spreads = vec![];
+        for _ in 0..3 {
+            spreads.push(stream.next().await.unwrap().unwrap().spread);
+        }
+        assert_eq!(spreads, vec![1.0, 2.0, 4.0]);
+
+        // once drained the client receives every summary again.
+        assert!(matches!(subscriber.deliver(&summary(5.0)), Delivery::Sent));
+        assert_eq!(stream.next().await.unwrap().unwrap().spread, 5.0);
+    }
+
+    #[tokio::test]
+    async fn evicted_client_receives_status() {
+        let (subscriber, mut stream) = Subscriber::new(1, None, View::default());
+        subscriber.deliver(&summary(1.0));
+        subscriber.evict("too slow".into());
+        drop(subscriber);
+
+        assert_eq!(stream.next().await.unwrap().unwrap().spread, 1.0);
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn throttled_client_receives_latest_per_interval() {
+        let interval = Duration::from_millis(200);
+        let (subscriber, mut stream) = Subscriber::new(1024, Some(interval), View::default());
+        let start = Instant::now();
+        subscriber.deliver(&summary(1.0));
+        assert_eq!(stream.next().await.unwrap().unwrap().spread, 1.0);
+
+        for spread in 2..10 {
+            subscriber.deliver(&summary(spread as f64));
+        }
+        assert_eq!(stream.next().await.unwrap().unwrap().spread, 9.0);
+        assert!(start.elapsed() >= interval);
+
+        // nothing new was published, so nothing is delivered.
+        assert!(timeout(interval * 2, stream.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn throttled_client_lags_once_its_interval_is_over() {
+        let interval = Duration::from_millis(100);
+        let (subscriber, mut stream) = Subscriber::new(1024, Some(interval), View::default());
+        subscriber.deliver(&summary(1.0));
+        assert_eq!(stream.next().await.unwrap().unwrap().spread, 1.0);
+
+        // the summaries replaced within the interval are not lag.
+        for spread in 2..10 {
+            assert!(matches!(
+                subscriber.deliver(&summary(spread as f64)),
+                Delivery::Sent
+            ));
+        }
+        assert_eq!(stream.next().await.unwrap().unwrap().spread, 9.0);
+
+        // the client stops reading, the summaries it does not take once rested are conflated.
+        sleep(interval * 2).await;
+        assert!(matches!(subscriber.deliver(&summary(10.0)), Delivery::Sent));
+        assert!(matches!(
+            subscriber.deliver(&summary(11.0)),
+            Delivery::Conflated(1)
+        ));
+        assert!(matches!(
+            subscriber.deliver(&summary(12.0)),
+            Delivery::Conflated(2)
+        ));
+    }
+}
@@ -1,6 +1,10 @@
 use futures::SinkExt;
 use log::error;
-use std::error::Error;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use std::{
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::time::{sleep, Duration};
 
 use futures::stream::SplitSink;
@@ -15,11 +19,47 @@ use crate::{
     orderbook::Level,
 };
 
+// Current time, in microseconds since the epoch.
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
 pub fn hash_key_offset() -> Decimal {
     Decimal::new(10000000000000000, 0)
 }
 
+// Price of a level once the taker fee of its exchange is paid: selling into a bid yields less and
+// buying from an ask costs more.
+pub fn fee_adjusted_price(
+    price: Decimal,
+    exchange: &str,
+    conf: &config::Server,
+    is_bids: bool,
+) -> Decimal {
+    let fee = conf
+        .exchanges
+        .get(exchange)
+        .and_then(|e| e.taker_fee)
+        .and_then(Decimal::from_f64)
+        .unwrap_or_default();
+    if is_bids {
+        price * (Decimal::ONE - fee)
+    } else {
+        price * (Decimal::ONE + fee)
+    }
+}
+
 pub fn map_key(k: (Decimal, Level), conf: &config::Server, is_bids: bool) -> (Decimal, Level) {
+    let k = if conf.fee_adjusted.unwrap_or(false) {
+        let (price, mut level) = k;
+        let price = fee_adjusted_price(price, &level.exchange, conf, is_bids);
+        level.effective_price = price.to_f64().unwrap_or(0.0);
+        (price, level)
+    } else {
+        k
+    };
     if (is_bids && conf.identical_level_order) || (!is_bids && !conf.identical_level_order) {
         (
             k.0 * hash_key_offset() + k.1.amount.to_string().parse::<Decimal>().unwrap(),
@@ -39,22 +79,22 @@ pub fn handle_binance_update_message(
     d: usize,
     is_bids: bool,
 ) {
-    let mut iter = v.into_iter().take(d);
+    let iter = v.into_iter().take(d);
     if is_bids {
         let b = &mut ob.bids;
         let other_b = &mut ob.asks;
         let other_b_clone = other_b.clone();
         let mut other_iter = other_b_clone.iter();
-        while let Some(l) = iter.next() {
+        for l in iter {
             let key = l.price();
             b.remove(&key);
             if l.amount() > 0.0 {
                 b.insert(key, ExchangeOrderbookLevel::Binance(l).into());
 
                 // check if a bid overlaps old ask levels
-                while let Some((k, _)) = other_iter.next() {
+                for (k, _) in other_iter.by_ref() {
                     if k <= &key {
-                        other_b.remove(&k);
+                        other_b.remove(k);
                     } else {
                         break;
                     }
@@ -66,16 +106,16 @@ pub fn handle_binance_update_message(
         let other_b = &mut ob.bids;
         let other_b_clone = other_b.clone();
         let mut other_iter = other_b_clone.iter().rev();
-        while let Some(l) = iter.next() {
+        for l in iter {
             let key = l.price();
             b.remove(&key);
             if l.amount() > 0.0 {
                 b.insert(key, ExchangeOrderbookLevel::Binance(l).into());
 
                 // check if a bid overlaps old ask levels
-                while let Some((k, _)) = other_iter.next() {
+                for (k, _) in other_iter.by_ref() {
                     if k >= &key {
-                        other_b.remove(&k);
+                        other_b.remove(k);
                     } else {
                         break;
                     }
@@ -170,27 +210,32 @@ mod tests {
     use std::collections::BTreeMap;
 
     #[test]
+    #[allow(clippy::cmp_owned, clippy::useless_conversion)]
     fn map_key() {
         let mut orderbook = Orderbook::new();
         let binance_bid_level = Level {
             amount: 10.10,
             exchange: "binance".into(),
             price: 100.222,
+            ..Default::default()
         };
         let bitstamp_bid_level = Level {
             amount: 20.20,
             exchange: "bitstamp".into(),
             price: 100.222,
+            ..Default::default()
         };
         let binance_ask_level = Level {
             amount: 10.10,
             exchange: "binance".into(),
             price: 100.333,
+            ..Default::default()
         };
         let bitstamp_ask_level = Level {
             amount: 20.20,
             exchange: "bitstamp".into(),
             price: 100.333,
+            ..Default::default()
         };
         orderbook
             .bids
@@ -204,7 +249,7 @@ mod tests {
         orderbook
             .asks
             .insert(Decimal::new(100333, 3), bitstamp_ask_level);
-        let mut conf: config::Server = config::read_config();
+        let mut conf = config::Server::builder().build();
 
         // explicitly set the identical level order for testing.
         conf.identical_level_order = true;
@@ -228,4 +273,40 @@ mod tests {
         assert!(bids.values().collect::<Vec<&Level>>()[0].amount == f64::from(10.10));
         assert!(asks.values().collect::<Vec<&Level>>()[0].amount == f64::from(10.10));
     }
+
+    #[test]
+    fn fee_adjusted_map_key() {
+        let level = |exchange: &str, price: f64| {
+            (
+                Decimal::try_from(price).unwrap(),
+                Level {
+                    amount: 1.0,
+                    exchange: exchange.into(),
+                    price,
+                    ..Default::default()
+                },
+            )
+        };
+        let mut conf = config::Server::builder().build();
+        conf.fee_adjusted = Some(true);
+        conf.exchanges.binance.taker_fee = Some(0.001);
+        conf.exchanges.bitstamp.taker_fee = Some(0.0005);
+
+        // at identical nominal prices the exchange with the lower fee is closer to the centre.
+        let bids: BTreeMap<Decimal, Level> = [level("binance", 100.0), level("bitstamp", 100.0)]
+            .into_iter()
+            .map(|k| super::map_key(k, &conf, true))
+            .collect();
+        let asks: BTreeMap<Decimal, Level> = [level("binance", 101.0), level("bitstamp", 101.0)]
+            .into_iter()
+            .map(|k| super::map_key(k, &conf, false))
+            .collect();
+        let best_bid = bids.values().next_back().unwrap();
+        let best_ask = asks.values().next().unwrap();
+        assert!(best_bid.exchange == "bitstamp");
+        assert!(best_bid.price == 100.0);
+        assert!(best_bid.effective_price == 99.95);
+        assert!(best_ask.exchange == "bitstamp");
+        assert!(best_ask.effective_price == 101.0505);
+    }
 }
@@ -0,0 +1,268 @@
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use std::{collections::BTreeMap, str::FromStr};
+
+use crate::{
+    analytics::AnalyticsParams,
+    definitions::Orderbook,
+    error::ObaggError,
+    orderbook::{ExchangeAmount, GroupedLevel, Summary, SummaryRequest},
+};
+
+// The shape of the aggregated book requested by a client. Clients sharing a view share the
+// Summary rendered by the aggregator.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct View {
+    // optional price bucket size the levels of all exchanges are grouped into.
+    pub tick: Option<Decimal>,
+    // each price appears once, with the amounts of all exchanges quoting it.
+    pub consolidated: bool,
+    // optional analytics added to the summary.
+    pub analytics: Option<AnalyticsParams>,
+}
+
+impl View {
+    pub fn from_request(req: &SummaryRequest) -> Result<Self, ObaggError> {
+        let tick = match req.tick.trim() {
+            "" => None,
+            tick => {
+                let tick = Decimal::from_str(tick)
+                    .map_err(|e| ObaggError(format!("Invalid tick : {e}")))?;
+                if tick <= Decimal::ZERO {
+                    return Err(ObaggError("Invalid tick : must be positive".into()));
+                }
+                Some(tick.normalize())
+            }
+        };
+        Ok(View {
+            tick,
+            consolidated: req.consolidated,
+            analytics: req.analytics.as_ref().map(AnalyticsParams::from),
+        })
+    }
+
+    // The default view is the per-exchange level view built from the reduced aggregated book,
+    // analytics are added on top of any view.
+    pub fn is_default(&self) -> bool {
+        self.tick.is_none() && !self.consolidated
+    }
+
+    // Render the view from the per-exchange orderbooks.
+    pub fn render(&self, books: &[&Orderbook], depth: usize) -> Summary {
+        let grouped_bids = group(books, self.tick, depth, true);
+        let grouped_asks = group(books, self.tick, depth, false);
+        let spread = match (grouped_asks.first(), grouped_bids.first()) {
+            (Some(ask), Some(bid)) => ask.price - bid.price,
+            _ => 0.0,
+        };
+        Summary {
+            spread,
+            grouped_bids,
+            grouped_asks,
+            ..Default::default()
+        }
+    }
+}
+
+// Group the levels of all books by price, rounded to the tick if any. Bids are rounded down and
+// asks rounded up so that a bucket never advertises a better price than its levels. The books hold
+// at most depth levels, the buckets reaching past the last level of a book cut to depth would
+// miss its levels beyond and are left out.
+fn group(
+    books: &[&Orderbook],
+    tick: Option<Decimal>,
+    depth: usize,
+    is_bids: bool,
+) -> Vec<GroupedLevel> {
+    let mut buckets: BTreeMap<Decimal, BTreeMap<&str, f64>> = BTreeMap::new();
+    // worst price of the books cut to depth, the buckets past it are incomplete.
+    let mut limit: Option<Decimal> = None;
+    for book in books {
+        let levels = if is_bids { &book.bids } else { &book.asks };
+        if levels.len() >= depth {
+            let last = if is_bids {
+                levels.keys().next()
+            } else {
+                levels.keys().next_back()
+            };
+            limit = match (limit, last.copied()) {
+                (Some(limit), Some(last)) if is_bids => Some(limit.max(last)),
+                (Some(limit), Some(last)) => Some(limit.min(last)),
+                (limit, last) => limit.or(last),
+            };
+        }
+        for (price, level) in levels {
+            let bucket = match tick {
+                Some(tick) if is_bids => (price / tick).floor() * tick,
+                Some(tick) => (price / tick).ceil() * tick,
+                None => *price,
+            };
+            *buckets
+                .entry(bucket)
+                .or_default()
+                .entry(level.exchange.as_str())
+                .or_default() += level.amount;
+        }
+    }
+    let grouped = |(price, exchanges): (Decimal, BTreeMap<&str, f64>)| GroupedLevel {
+        price: price.to_f64().unwrap_or(0.0),
+        amount: exchanges.values().sum(),
+        exchanges: exchanges
+            .into_iter()
+            .map(|(exchange, amount)| ExchangeAmount {
+                exchange: exchange.into(),
+                amount,
+            })
+            .collect(),
+    };
+    // a bid bucket holds the prices from its own up to the next tick, an ask bucket those from the
+    // previous tick up to its own.
+    let complete = |bucket: &Decimal| match limit {
+        Some(limit) if is_bids => *bucket >= limit,
+        Some(limit) => *bucket <= limit,
+        None => true,
+    };
+    if is_bids {
+        buckets
+            .into_iter()
+            .rev()
+            .filter(|(bucket, _)| complete(bucket))
+            .take(depth)
+            .map(grouped)
+            .collect()
+    } else {
+        buckets
+            .into_iter()
+            .filter(|(bucket, _)| complete(bucket))
+            .take(depth)
+            .map(grouped)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::View;
+    use crate::{definitions::Orderbook, orderbook::Level};
+
+    fn level(exchange: &str, price: f64, amount: f64) -> Level {
+        Level {
+            exchange: exchange.into(),
+            price,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tick_grouping() {
+        let mut binance = Orderbook::new();
+        binance
+            .bids
+            .insert(Decimal::new(25012, 7), level("binance", 0.0025012, 1.0));
+        binance
+            .bids
+            .insert(Decimal::new(25003, 7), level("binance", 0.0025003, 2.0));
+        binance
+            .asks
+            .insert(Decimal::new(25021, 7), level("binance", 0.0025021, 3.0));
+        let mut bitstamp = Orderbook::new();
+        bitstamp
+            .bids
+            .insert(Decimal::new(25015, 7), level("bitstamp", 0.0025015, 4.0));
+        bitstamp
+            .asks
+            .insert(Decimal::new(25029, 7), level("bitstamp", 0.0025029, 5.0));
+
+        let view = View {
+            tick: Some(Decimal::new(1, 5)),
+            ..Default::default()
+        };
+        let summary = view.render(&[&binance, &bitstamp], 10);
+
+        // bids are rounded down, asks rounded up.
+        assert_eq!(summary.grouped_bids.len(), 1);
+        assert_eq!(summary.grouped_bids[0].price, 0.0025);
+        assert_eq!(summary.grouped_bids[0].amount, 7.0);
+        assert_eq!(summary.grouped_bids[0].exchanges.len(), 2);
+        assert_eq!(summary.grouped_bids[0].exchanges[0].exchange, "binance");
+        assert_eq!(summary.grouped_bids[0].exchanges[0].amount, 3.0);
+        assert_eq!(summary.grouped_asks.len(), 1);
+        assert_eq!(summary.grouped_asks[0].price, 0.00251);
+        assert_eq!(summary.grouped_asks[0].amount, 8.0);
+        assert!(summary.bids.is_empty() && summary.asks.is_empty());
+    }
+
+    #[test]
+    fn consolidated() {
+        let mut binance = Orderbook::new();
+        binance
+            .bids
+            .insert(Decimal::new(25012, 7), level("binance", 0.0025012, 1.0));
+        binance
+            .bids
+            .insert(Decimal::new(25003, 7), level("binance", 0.0025003, 2.0));
+        let mut bitstamp = Orderbook::new();
+        bitstamp
+            .bids
+            .insert(Decimal::new(25012, 7), level("bitstamp", 0.0025012, 4.0));
+        bitstamp
+            .bids
+            .insert(Decimal::new(25001, 7), level("bitstamp", 0.0025001, 5.0));
+
+        let view = View {
+            consolidated: true,
+            ..Default::default()
+        };
+        let summary = view.render(&[&binance, &bitstamp], 2);
+
+        // depth counts distinct prices.
+        assert_eq!(summary.grouped_bids.len(), 2);
+        assert_eq!(summary.grouped_bids[0].price, 0.0025012);
+        assert_eq!(summary.grouped_bids[0].amount, 5.0);
+        assert_eq!(summary.grouped_bids[0].exchanges.len(), 2);
+        assert_eq!(summary.grouped_bids[1].price, 0.0025003);
+        assert_eq!(summary.grouped_bids[1].exchanges[0].exchange, "binance");
+    }
+
+    #[test]
+    fn buckets_past_a_book_cut_to_depth_are_left_out() {
+        let book = |exchange: &str, bids: &[(i64, f64)], asks: &[(i64, f64)]| {
+            let mut book = Orderbook::new();
+            for &(price, amount) in bids {
+                let price = Decimal::new(price, 1);
+                book.bids
+                    .insert(price, level(exchange, price.try_into().unwrap(), amount));
+            }
+            for &(price, amount) in asks {
+                let price = Decimal::new(price, 1);
+                book.asks
+                    .insert(price, level(exchange, price.try_into().unwrap(), amount));
+            }
+            book
+        };
+        // binance holds 2 levels a side, the depth, its levels below 99.8 and above 102.5 are
+        // unknown.
+        let binance = book(
+            "binance",
+            &[(1005, 1.0), (998, 2.0)],
+            &[(1012, 3.0), (1025, 4.0)],
+        );
+        let bitstamp = book("bitstamp", &[(1002, 5.0)], &[(1017, 6.0)]);
+
+        let view = View {
+            tick: Some(Decimal::ONE),
+            ..Default::default()
+        };
+        let summary = view.render(&[&binance, &bitstamp], 2);
+
+        // the 99 and 103 buckets would miss the binance levels past its depth.
+        assert_eq!(summary.grouped_bids.len(), 1);
+        assert_eq!(summary.grouped_bids[0].price, 100.0);
+        assert_eq!(summary.grouped_bids[0].amount, 6.0);
+        assert_eq!(summary.grouped_asks.len(), 1);
+        assert_eq!(summary.grouped_asks[0].price, 102.0);
+        assert_eq!(summary.grouped_asks[0].amount, 9.0);
+    }
+}
@@ -0,0 +1,140 @@
+// End-to-end tests of the gRPC server, run with the test-harness feature.
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+use obagg::{
+    harness::{summaries, Harness},
+    mock::{self, binance_depth, bitstamp_book, bitstamp_subscribed, MockExchange, Step},
+    orderbook::{Level, Summary, SummaryRequest},
+};
+
+fn level(exchange: &str, price: f64, amount: f64) -> Level {
+    Level {
+        exchange: exchange.into(),
+        price,
+        amount,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn summaries_fan_out_to_clients_until_they_disconnect() {
+    let harness = Harness::start(mock::conf(10, None, None)).await;
+    let mut first = harness.subscribe(SummaryRequest::default()).await;
+    let mut second = harness.subscribe(SummaryRequest::default()).await;
+
+    let book = binance_depth(1, &[("0.0024", "10")], &[("0.0026", "5")]);
+    harness.send_binance(&book).await;
+    let book = bitstamp_book(1_000, &[("0.0025", "3")], &[("0.0027", "1")]);
+    harness.send_bitstamp(&book).await;
+
+    let expected = vec![
+        Summary {
+            spread: 0.0026 - 0.0024,
+            bids: vec![level("binance", 0.0024, 10.0)],
+            asks: vec![level("binance", 0.0026, 5.0)],
+            ..Default::default()
+        },
+        Summary {
+            spread: 0.0026 - 0.0025,
+            bids: vec![
+                level("bitstamp", 0.0025, 3.0),
+                level("binance", 0.0024, 10.0),
+            ],
+            asks: vec![
+                level("binance", 0.0026, 5.0),
+                level("bitstamp", 0.0027, 1.0),
+            ],
+            ..Default::default()
+        },
+    ];
+    assert_eq!(summaries(&mut first, 2).await, expected);
+    assert_eq!(summaries(&mut second, 2).await, expected);
+
+    // the producer pool entry of a disconnected client is removed.
+    drop(first);
+    assert!(harness.until_clients(1).await);
+    let book = binance_depth(2, &[("0.0023", "1")], &[("0.0026", "5")]);
+    harness.send_binance(&book).await;
+    let received = summaries(&mut second, 1).await;
+    assert_eq!(received[0].bids[1], level("binance", 0.0023, 1.0));
+}
+
+#[tokio::test]
+async fn nothing_is_published_before_the_ticker_is_quoted() {
+    let harness = Harness::start(mock::conf(10, None, None)).await;
+    let mut stream = harness.subscribe(SummaryRequest::default()).await;
+
+    let book = binance_depth(1, &[("65000", "1")], &[("65001", "2")]);
+    harness.send_market("binance", "btcusdt", &book).await;
+    let book = binance_depth(1, &[("0.0024", "10")], &[("0.0026", "5")]);
+    harness.send_binance(&book).await;
+
+    // the aggregator survived the book of the other market and serves the ticker.
+    let received = summaries(&mut stream, 1).await;
+    assert_eq!(received[0].bids, vec![level("binance", 0.0024, 10.0)]);
+    assert_eq!(received[0].asks, vec![level("binance", 0.0026, 5.0)]);
+}
+
+#[tokio::test]
+async fn server_aggregates_the_mock_exchange_feeds() {
+    let (binance_go, bitstamp_go) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+    let binance = MockExchange::start(
+        vec![vec![
+            Step::Wait(binance_go.clone()),
+            Step::Send(binance_depth(1, &[("0.0024", "10")], &[("0.0026", "5")])),
+        ]],
+        vec![],
+    )
+    .await;
+    let bitstamp = MockExchange::start(
+        vec![vec![
+            Step::Send(bitstamp_subscribed("order_book_ltcbtc")),
+            Step::Wait(bitstamp_go.clone()),
+            Step::Send(bitstamp_book(1_000, &[("0.0025", "3")], &[("0.0027", "1")])),
+        ]],
+        vec![],
+    )
+    .await;
+    let conf = mock::conf(10, Some(&binance), Some(&bitstamp));
+    let harness = Harness::start_server(conf).await;
+    let mut stream = harness.subscribe(SummaryRequest::default()).await;
+
+    // the consumers subscribed to the partial book stream and the bitstamp channel.
+    assert!(binance.until(|m| m.connections() == 1).await);
+    assert!(bitstamp.until(|m| m.requests().len() == 2).await);
+    assert_eq!(binance.requests(), vec!["/ws/ltcbtc@depth10@100ms"]);
+    assert!(bitstamp.requests()[1].contains("order_book_ltcbtc"));
+
+    binance_go.notify_one();
+    let received = summaries(&mut stream, 1).await;
+    assert_eq!(received[0].bids, vec![level("binance", 0.0024, 10.0)]);
+    bitstamp_go.notify_one();
+    let received = summaries(&mut stream, 1).await;
+    assert_eq!(
+        received[0].bids,
+        vec![
+            level("bitstamp", 0.0025, 3.0),
+            level("binance", 0.0024, 10.0)
+        ]
+    );
+    assert_eq!(received[0].asks[1], level("bitstamp", 0.0027, 1.0));
+}
+
+#[tokio::test]
+async fn client_connecting_mid_stream_gets_the_next_books() {
+    let harness = Harness::start(mock::conf(10, None, None)).await;
+    let mut early = harness.subscribe(SummaryRequest::default()).await;
+    let book = |id, bid: &str| binance_depth(id, &[(bid, "1")], &[("0.0030", "1")]);
+    harness.send_binance(&book(1, "0.0021")).await;
+    harness.send_binance(&book(2, "0.0022")).await;
+    assert_eq!(summaries(&mut early, 2).await.len(), 2);
+
+    let mut late = harness.subscribe(SummaryRequest::default()).await;
+    harness.send_binance(&book(3, "0.0023")).await;
+    harness.send_binance(&book(4, "0.0024")).await;
+    let bids =
+        |received: Vec<Summary>| -> Vec<f64> { received.iter().map(|s| s.bids[0].price).collect() };
+    assert_eq!(bids(summaries(&mut late, 2).await), vec![0.0023, 0.0024]);
+    assert_eq!(bids(summaries(&mut early, 2).await), vec![0.0023, 0.0024]);
+}